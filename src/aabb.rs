@@ -24,6 +24,17 @@ impl AABB {
         t_min.max(t_small.max()) < t_max.min(t_big.min())        
     }
 
+    // Parameter range of the ray inside the box, clipped to [t_min, t_max]
+    pub fn clip(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        let inv_d = vec_one().component_div(&ray.direction());
+        let t0 = (self.min - ray.origin()).component_mul(&inv_d);
+        let t1 = (self.max - ray.origin()).component_mul(&inv_d);
+
+        let t_enter = t_min.max(t0.zip_map(&t1, |a, b| a.min(b)).max());
+        let t_exit = t_max.min(t0.zip_map(&t1, |a, b| a.max(b)).min());
+        if t_enter < t_exit { Some((t_enter, t_exit)) } else { None }
+    }
+
     pub fn zero() -> Self {
         AABB {
             min: vec_zero(),
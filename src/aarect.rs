@@ -1,8 +1,11 @@
 use crate::aabb::AABB;
 use crate::hittable::{HitRecord, Hittable};
+use crate::light::Shape;
+use crate::light_bvh::DirectionCone;
 use crate::material::Material;
 use crate::ray::Ray;
-use crate::vec::{vec, vec3};
+use crate::stats::{self, Counter};
+use crate::vec::{vec, vec2, vec3};
 
 use nalgebra::{Vector2, Vector3};
 use std::f32;
@@ -24,6 +27,7 @@ pub struct AARect {
 
 impl Hittable for AARect {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        stats::inc(Counter::PrimitiveTests);
         use AARectType::*;
         let t = match &self.rect_type {
             XY => (self.k - ray.origin().z) / ray.direction().z,
@@ -68,4 +72,25 @@ impl Hittable for AARect {
             YZ => Some(AABB { min: min.zxy(), max: max.zxy() }),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Shape for AARect {
+    fn point(&self, uv: Vector2<f32>) -> (Vector3<f32>, Vector3<f32>, f32) {
+        use AARectType::*;
+        let xy = self.xy0 + uv.component_mul(&(self.xy1 - self.xy0));
+        match &self.rect_type {
+            XY => (vec3(xy.x, xy.y, self.k), vec(0.0, 0.0, 1.0), self.area()),
+            XZ => (vec3(xy.x, self.k, xy.y), vec(0.0, -1.0, 0.0), self.area()),
+            YZ => (vec3(self.k, xy.x, xy.y), vec(1.0, 0.0, 0.0), self.area()),
+        }
+    }
+
+    fn area(&self) -> f32 {
+        let size = self.xy1 - self.xy0;
+        (size.x * size.y).abs()
+    }
+
+    fn normal_bounds(&self) -> DirectionCone {
+        DirectionCone::new(self.point(vec2(0.5, 0.5)).1, 1.0)
+    }
+}
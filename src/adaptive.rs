@@ -0,0 +1,84 @@
+use image::ImageBuffer;
+use nalgebra::Vector3;
+
+use crate::utils::clamp;
+use crate::vec::{luminance, vec3};
+
+// Running statistics for a single pixel, used to decide when it has converged.
+// The mean and variance are tracked on luminance with Welford's algorithm so we
+// don't have to keep the individual samples around.
+#[derive(Clone, Copy, Default)]
+pub struct PixelStats {
+    pub samples: u32,
+    pub converged: bool,
+    pub mean: f32,
+    pub m2: f32,
+}
+
+impl PixelStats {
+    pub fn add_sample(&mut self, color: Vector3<f32>) {
+        let x = luminance(color);
+        self.samples += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.samples as f32;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    pub fn variance(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        self.m2 / (self.samples - 1) as f32
+    }
+
+    // Standard error of the mean, measured after the sqrt tone curve used for
+    // display, so dark and bright regions are judged by how noisy they look.
+    pub fn error(&self) -> f32 {
+        let std_err = (self.variance() / self.samples as f32).sqrt();
+        std_err / (2.0 * self.mean.max(0.0).sqrt()).max(1e-3)
+    }
+
+    pub fn update_convergence(&mut self, min_samples: u32, threshold: f32) {
+        self.converged = self.samples >= min_samples && self.error() < threshold;
+    }
+
+    // Samples for the next pass of a pixel that hasn't converged, more the
+    // further its error is above the threshold so the noisiest pixels catch up
+    pub fn pass_samples(&self, min_samples: u32, threshold: f32, max_samples: u32) -> u32 {
+        if self.samples < min_samples {
+            return 1;
+        }
+        ((self.error() / threshold).ceil() as u32).clamp(1, max_samples)
+    }
+}
+
+// Blue (few samples) to red (many samples) ramp for the convergence heatmap
+pub fn heatmap_color(t: f32) -> Vector3<f32> {
+    let t = clamp(t, 0.0, 1.0);
+    let r = clamp(1.5 - (4.0 * t - 3.0).abs(), 0.0, 1.0);
+    let g = clamp(1.5 - (4.0 * t - 2.0).abs(), 0.0, 1.0);
+    let b = clamp(1.5 - (4.0 * t - 1.0).abs(), 0.0, 1.0);
+    vec3(r, g, b)
+}
+
+pub fn heatmap_buffer(stats: &[PixelStats]) -> Vec<Vector3<f32>> {
+    let max_samples = stats.iter().map(|s| s.samples).max().unwrap_or(1).max(1);
+    stats
+        .iter()
+        .map(|s| heatmap_color(s.samples as f32 / max_samples as f32))
+        .collect()
+}
+
+pub fn save_heatmap(stats: &[PixelStats], nx: u32, ny: u32, path: &str) {
+    let colors = heatmap_buffer(stats);
+    let mut imgbuf = ImageBuffer::new(nx, ny);
+    for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
+        let c = colors[(y * nx + x) as usize];
+        *pixel = image::Rgb([
+            (c.x * 255.99) as u8,
+            (c.y * 255.99) as u8,
+            (c.z * 255.99) as u8,
+        ]);
+    }
+    imgbuf.save(path).unwrap();
+}
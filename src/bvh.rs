@@ -4,6 +4,7 @@ use std::{sync::Arc, f32};
 use crate::aabb::{surrounding_box, AABB};
 use crate::hittable::{HitRecord, Hittable, HittableList};
 use crate::ray::Ray;
+use crate::stats::{self, Counter};
 
 const MAX_LEAF: usize = 2;
 
@@ -85,6 +86,7 @@ impl BVHNode {
 
 impl Hittable for BVHNode {
     fn hit(&self, ray: &Ray, t_min: f32, mut t_max: f32) -> Option<HitRecord> {
+        stats::inc(Counter::BvhNodeVisits);
         if self.bbox.hit(ray, t_min, t_max) {
             let left_hit = self.left.hit(ray, t_min, t_max);
 
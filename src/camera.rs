@@ -1,13 +1,24 @@
 use crate::ray::Ray;
 use crate::vec::{deg_to_rad, random_unit_in_disk};
 
-use nalgebra::Vector3;
+use nalgebra::{Rotation3, Unit, Vector3};
 
 pub enum ApertureShape {
     Circle,
     Hexagon
 }
 
+// The user-editable part of a camera, enough to restore a view
+#[derive(Clone, Copy, Debug)]
+pub struct CameraView {
+    pub origin: Vector3<f32>,
+    pub lookat: Vector3<f32>,
+    pub vup: Vector3<f32>,
+    pub vfov: f32,
+    pub aperture: f32,
+    pub focus_dist: f32,
+}
+
 pub struct Camera {
     pub origin: Vector3<f32>,
     pub lower_left_corner: Vector3<f32>,
@@ -17,7 +28,12 @@ pub struct Camera {
     v: Vector3<f32>,
     w: Vector3<f32>,
     pub lens_radius: f32,
-    pub aperture_shape: ApertureShape
+    pub aperture_shape: ApertureShape,
+    pub lookat: Vector3<f32>,
+    pub vup: Vector3<f32>,
+    pub vfov: f32,
+    pub aspect: f32,
+    pub focus_dist: f32,
 }
 
 impl Camera {
@@ -31,32 +47,107 @@ impl Camera {
         focus_dist: f32
     ) -> Camera {
         use ApertureShape::*;
-        let lens_radius = aperture / 2.0;
+        let mut camera = Camera {
+            origin,
+            lower_left_corner: Vector3::zeros(),
+            horizontal: Vector3::zeros(),
+            vertical: Vector3::zeros(),
+            u: Vector3::zeros(),
+            v: Vector3::zeros(),
+            w: Vector3::zeros(),
+            lens_radius: aperture / 2.0,
+            aperture_shape: Circle,
+            lookat,
+            vup,
+            vfov,
+            aspect,
+            focus_dist,
+        };
+        camera.update();
+        camera
+    }
 
-        let theta = deg_to_rad(vfov);
+    // Recomputes the image plane, call after changing any of the view parameters
+    pub fn update(&mut self) {
+        let theta = deg_to_rad(self.vfov);
         let half_height = (theta / 2.0).tan();
-        let half_width = aspect * half_height;       
+        let half_width = self.aspect * half_height;
 
-        let w = (origin - lookat).normalize();
-        let u = vup.cross(&w).normalize();
-        let v = w.cross(&u);
+        self.w = (self.origin - self.lookat).normalize();
+        self.u = self.vup.cross(&self.w).normalize();
+        self.v = self.w.cross(&self.u);
 
-        let lower_left_corner = origin 
-                              - half_width * focus_dist * u 
-                              - half_height * focus_dist * v 
-                              - focus_dist * w;
-        let horizontal = 2.0 * half_width * focus_dist * u;
-        let vertical = 2.0 * half_height * focus_dist * v;
+        self.lower_left_corner = self.origin
+                               - half_width * self.focus_dist * self.u
+                               - half_height * self.focus_dist * self.v
+                               - self.focus_dist * self.w;
+        self.horizontal = 2.0 * half_width * self.focus_dist * self.u;
+        self.vertical = 2.0 * half_height * self.focus_dist * self.v;
+    }
 
-        Camera {
-            origin,
-            lower_left_corner,
-            horizontal,
-            vertical,
-            u, v, w, lens_radius,
-            aperture_shape: Circle
+    pub fn view(&self) -> CameraView {
+        CameraView {
+            origin: self.origin,
+            lookat: self.lookat,
+            vup: self.vup,
+            vfov: self.vfov,
+            aperture: self.aperture(),
+            focus_dist: self.focus_dist,
         }
     }
+
+    pub fn set_view(&mut self, view: CameraView) {
+        self.origin = view.origin;
+        self.lookat = view.lookat;
+        self.vup = view.vup;
+        self.vfov = view.vfov;
+        self.focus_dist = view.focus_dist;
+        self.set_aperture(view.aperture);
+        self.update();
+    }
+
+    pub fn aperture(&self) -> f32 {
+        self.lens_radius * 2.0
+    }
+
+    pub fn set_aperture(&mut self, aperture: f32) {
+        self.lens_radius = aperture.max(0.0) / 2.0;
+    }
+
+    // Rotates the origin around the look-at point, angles in degrees
+    pub fn orbit(&mut self, yaw: f32, pitch: f32) {
+        let offset = self.origin - self.lookat;
+        let radius = offset.magnitude();
+        let up = self.vup.normalize();
+        let current_pitch = (offset.dot(&up) / radius).clamp(-1.0, 1.0).asin();
+        // Stay away from the poles so the view doesn't flip
+        let max_pitch = deg_to_rad(89.0);
+        let new_pitch = (current_pitch + deg_to_rad(pitch)).clamp(-max_pitch, max_pitch);
+
+        let yaw_rotation = Rotation3::from_axis_angle(&Unit::new_normalize(up), deg_to_rad(yaw));
+        let horizontal_dir = yaw_rotation * (offset - offset.dot(&up) * up).normalize();
+        self.origin = self.lookat + radius * (new_pitch.cos() * horizontal_dir + new_pitch.sin() * up);
+        self.update();
+    }
+
+    // Moves origin and look-at point in the image plane, in units of the focus plane height
+    pub fn pan(&mut self, dx: f32, dy: f32) {
+        let scale = self.vertical.magnitude();
+        let offset = scale * (dx * self.u + dy * self.v);
+        self.origin += offset;
+        self.lookat += offset;
+        self.update();
+    }
+
+    // Moves towards the look-at point by a fraction of the current distance
+    pub fn dolly(&mut self, amount: f32) {
+        let offset = self.origin - self.lookat;
+        let distance = offset.magnitude();
+        let new_distance = (distance * (1.0 - amount)).max(1e-3);
+        self.origin = self.lookat + offset * (new_distance / distance);
+        self.update();
+    }
+
     pub fn get_ray(&self, s: f32, t: f32) -> Ray {
         use ApertureShape::*;
         let rd = match &self.aperture_shape {
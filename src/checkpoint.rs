@@ -0,0 +1,168 @@
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::adaptive::PixelStats;
+use crate::camera::CameraView;
+use nalgebra::Vector3;
+
+const MAGIC: &[u8; 4] = b"RRCK";
+const VERSION: u32 = 2;
+// Magic, version, size, samples, seed and camera view
+const HEADER_BYTES: usize = 4 + 4 + 4 + 4 + 4 + 8 + 4 * 12;
+// RGB accumulation and the pixel's statistics
+const PIXEL_BYTES: usize = 4 * 3 + 4 + 1 + 4 + 4;
+
+// Everything needed to pick up a progressive render where it left off: the
+// float accumulation buffer, per-pixel sample statistics, the number of
+// completed passes, the seed the pixel jitter is derived from and the camera
+// view, which may have been changed in the preview window.
+//
+// Scattering still uses `thread_rng`, which can't be captured, so a resumed
+// render is statistically equivalent to an uninterrupted one but not
+// bit-identical.
+pub struct Checkpoint {
+    pub width: u32,
+    pub height: u32,
+    pub completed_passes: u32,
+    pub seed: u64,
+    pub view: CameraView,
+    pub image_buf: Vec<f32>,
+    pub pixel_stats: Vec<PixelStats>,
+}
+
+impl Checkpoint {
+    // Writes to a temporary file first and renames it over the old checkpoint,
+    // so a crash while saving never leaves a truncated checkpoint behind.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp_path = format!("{}.tmp", path);
+        {
+            let mut w = BufWriter::new(fs::File::create(&temp_path)?);
+            w.write_all(MAGIC)?;
+            write_u32(&mut w, VERSION)?;
+            write_u32(&mut w, self.width)?;
+            write_u32(&mut w, self.height)?;
+            write_u32(&mut w, self.completed_passes)?;
+            w.write_all(&self.seed.to_le_bytes())?;
+            write_vec(&mut w, self.view.origin)?;
+            write_vec(&mut w, self.view.lookat)?;
+            write_vec(&mut w, self.view.vup)?;
+            write_f32(&mut w, self.view.vfov)?;
+            write_f32(&mut w, self.view.aperture)?;
+            write_f32(&mut w, self.view.focus_dist)?;
+            for v in &self.image_buf {
+                write_f32(&mut w, *v)?;
+            }
+            for stats in &self.pixel_stats {
+                write_u32(&mut w, stats.samples)?;
+                w.write_all(&[stats.converged as u8])?;
+                write_f32(&mut w, stats.mean)?;
+                write_f32(&mut w, stats.m2)?;
+            }
+            w.flush()?;
+        }
+        fs::rename(temp_path, path)
+    }
+
+    pub fn load(path: &str) -> io::Result<Checkpoint> {
+        let file = fs::File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut r = BufReader::new(file);
+
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a render checkpoint"));
+        }
+        let version = read_u32(&mut r)?;
+        if version != VERSION {
+            return Err(invalid_data(&format!("unsupported checkpoint version {}", version)));
+        }
+
+        let width = read_u32(&mut r)?;
+        let height = read_u32(&mut r)?;
+        let completed_passes = read_u32(&mut r)?;
+        let mut seed = [0u8; 8];
+        r.read_exact(&mut seed)?;
+        let seed = u64::from_le_bytes(seed);
+        let view = CameraView {
+            origin: read_vec(&mut r)?,
+            lookat: read_vec(&mut r)?,
+            vup: read_vec(&mut r)?,
+            vfov: read_f32(&mut r)?,
+            aperture: read_f32(&mut r)?,
+            focus_dist: read_f32(&mut r)?,
+        };
+
+        // Check the size against the file before allocating for it
+        let pixels = (width as usize)
+            .checked_mul(height as usize)
+            .filter(|&pixels| {
+                pixels.checked_mul(PIXEL_BYTES).and_then(|b| b.checked_add(HEADER_BYTES)).map(|b| b as u64) == Some(file_len)
+            })
+            .ok_or_else(|| invalid_data(&format!("{}x{} doesn't match the checkpoint size of {} bytes", width, height, file_len)))?;
+        let image_buf = (0..pixels * 3)
+            .map(|_| read_f32(&mut r))
+            .collect::<io::Result<Vec<f32>>>()?;
+        let pixel_stats = (0..pixels)
+            .map(|_| {
+                let samples = read_u32(&mut r)?;
+                let mut converged = [0u8; 1];
+                r.read_exact(&mut converged)?;
+                Ok(PixelStats {
+                    samples,
+                    converged: converged[0] != 0,
+                    mean: read_f32(&mut r)?,
+                    m2: read_f32(&mut r)?,
+                })
+            })
+            .collect::<io::Result<Vec<PixelStats>>>()?;
+
+        Ok(Checkpoint {
+            width,
+            height,
+            completed_passes,
+            seed,
+            view,
+            image_buf,
+            pixel_stats,
+        })
+    }
+}
+
+fn write_u32(w: &mut impl Write, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_f32(w: &mut impl Write, v: f32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_vec(w: &mut impl Write, v: Vector3<f32>) -> io::Result<()> {
+    write_f32(w, v.x)?;
+    write_f32(w, v.y)?;
+    write_f32(w, v.z)
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32(r: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+fn read_vec(r: &mut impl Read) -> io::Result<Vector3<f32>> {
+    Ok(Vector3::new(read_f32(r)?, read_f32(r)?, read_f32(r)?))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -2,8 +2,9 @@ use nalgebra::{Vector2, Vector3, Rotation3};
 use std::{sync::Arc, f32};
 
 use crate::aabb::{surrounding_box, AABB};
+use crate::light::ALL_LIGHT_GROUPS;
 use crate::material::Material;
-use crate::ray::Ray;
+use crate::ray::{Ray, RayKind};
 use crate::vec::{vec, vec_zero, deg_to_rad};
 
 pub struct HitRecord {
@@ -12,7 +13,9 @@ pub struct HitRecord {
     pub normal: Vector3<f32>,
     pub front_face: bool,
     pub material: Arc<dyn Material>,
-    pub uv: Vector2<f32>
+    pub uv: Vector2<f32>,
+    // Lights that illuminate the hit object, see Visibility
+    pub light_groups: u32,
 }
 
 impl HitRecord {
@@ -36,7 +39,8 @@ impl HitRecord {
             front_face,
             normal,
             material: material,
-            uv
+            uv,
+            light_groups: ALL_LIGHT_GROUPS,
         }
     }
 
@@ -127,6 +131,57 @@ impl Hittable for FlipFace {
     }
 }
 
+// Hides an object from some kinds of rays, e.g. a light blocker the camera
+// doesn't see or a backdrop that doesn't show up in reflections. Hidden
+// objects are skipped, rays find whatever is behind them.
+//
+// Light linking: the object is only lit by lights with a light group in
+// light_groups, a bit mask like Light::light_groups. Nested wrappers
+// intersect their masks.
+pub struct Visibility {
+    pub object: Arc<dyn Hittable>,
+    pub camera: bool,
+    // Casts shadows from sampled lights
+    pub shadow: bool,
+    // Seen in reflections and through glass, rough or smooth
+    pub specular: bool,
+    // Seen by diffuse and volume scattering, so it lights and shades others
+    pub diffuse: bool,
+    pub light_groups: u32,
+}
+
+impl Visibility {
+    pub fn new(obj: impl Hittable + 'static) -> Self {
+        Visibility::new_b(Arc::new(obj))
+    }
+
+    pub fn new_b(object: Arc<dyn Hittable>) -> Self {
+        Self { object, camera: true, shadow: true, specular: true, diffuse: true, light_groups: ALL_LIGHT_GROUPS }
+    }
+}
+
+impl Hittable for Visibility {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let visible = match ray.kind {
+            RayKind::Camera => self.camera,
+            RayKind::Shadow => self.shadow,
+            RayKind::Specular | RayKind::Glossy => self.specular,
+            RayKind::Diffuse => self.diffuse,
+            RayKind::Probe => true,
+        };
+        if !visible {
+            return None;
+        }
+        let mut hit_rec = self.object.hit(ray, t_min, t_max)?;
+        hit_rec.light_groups &= self.light_groups;
+        Some(hit_rec)
+    }
+
+    fn bounding_box(&self) -> Option<AABB> {
+        self.object.bounding_box()
+    }
+}
+
 pub struct Transform {
     pub object: Arc<dyn Hittable>,
     pub offset: Vector3<f32>,
@@ -197,6 +252,9 @@ impl Hittable for Transform {
         let inv_rot = self.rotation.inverse();
         let mut moved_ray = Ray::new(inv_rot * (ray.origin() - self.offset), inv_rot * ray.direction());
         moved_ray.albedo_normal_ray = ray.albedo_normal_ray;
+        moved_ray.wavelengths = ray.wavelengths;
+        moved_ray.media = ray.media.clone();
+        moved_ray.kind = ray.kind;
 
         if let Some(mut hit_rec) = self.object.hit(&moved_ray, t_min, t_max) {
             hit_rec.p = self.rotation * hit_rec.p + self.offset;
@@ -0,0 +1,186 @@
+use std::f32;
+use std::fs;
+use std::io;
+
+// Photometric data of a luminaire from an IES LM-63 file. Only type C
+// photometry is supported, which is what virtually all architectural fixtures
+// use: vertical angles go from 0 at the nadir (straight down) to 180 at the
+// zenith, horizontal angles around the vertical axis. Intensities are in
+// candela, with the file's multiplier already applied.
+pub struct IesProfile {
+    vertical: Vec<f32>,
+    horizontal: Vec<f32>,
+    // candela[h][v]
+    candela: Vec<Vec<f32>>,
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl IesProfile {
+    pub fn load(path: &str) -> io::Result<Self> {
+        IesProfile::parse(&fs::read_to_string(path)?).map_err(|e| invalid_data(format!("{}: {}", path, e)))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        // Keywords come first, the numbers follow the TILT line
+        let mut lines = text.lines();
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT=") => break line.trim()[5..].to_string(),
+                Some(_) => continue,
+                None => return Err(String::from("no TILT line, not an IES file")),
+            }
+        };
+        let rest = lines.collect::<Vec<&str>>().join(" ");
+        let mut numbers = rest
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f32>().map_err(|e| format!("bad number {:?}: {}", s, e)))
+            .collect::<Result<Vec<f32>, String>>()?
+            .into_iter();
+        let mut next = || numbers.next().ok_or_else(|| String::from("unexpected end of file"));
+
+        // Lamp to luminaire geometry and the tilt table, not used
+        if tilt == "INCLUDE" {
+            next()?;
+            let pairs = next()? as usize;
+            for _ in 0..2 * pairs {
+                next()?;
+            }
+        }
+
+        let _lamps = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let vertical_count = next()? as usize;
+        let horizontal_count = next()? as usize;
+        let photometric_type = next()? as u32;
+        // Units and luminous opening dimensions
+        for _ in 0..4 {
+            next()?;
+        }
+        let ballast_factor = next()?;
+        // Ballast lamp factor (or future use) and input watts
+        next()?;
+        next()?;
+        if photometric_type != 1 {
+            return Err(format!("photometric type {} isn't supported, only type C", photometric_type));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(String::from("no angles"));
+        }
+        // The counts come from the file, check them against what is left
+        // before allocating anything
+        let needed = vertical_count
+            .checked_mul(horizontal_count)
+            .and_then(|n| n.checked_add(vertical_count))
+            .and_then(|n| n.checked_add(horizontal_count));
+        if needed.is_none_or(|n| n > numbers.len()) {
+            return Err(format!("{} vertical and {} horizontal angles don't fit the data", vertical_count, horizontal_count));
+        }
+
+        let mut next = || numbers.next().ok_or_else(|| String::from("unexpected end of file"));
+        let vertical = (0..vertical_count).map(|_| next()).collect::<Result<Vec<f32>, String>>()?;
+        let horizontal = (0..horizontal_count).map(|_| next()).collect::<Result<Vec<f32>, String>>()?;
+        let mut candela = Vec::with_capacity(horizontal_count);
+        for _ in 0..horizontal_count {
+            let row = (0..vertical_count).map(|_| next().map(|c| c * multiplier * ballast_factor)).collect::<Result<Vec<f32>, String>>()?;
+            candela.push(row);
+        }
+        Ok(IesProfile { vertical, horizontal, candela })
+    }
+
+    // Candela at a vertical and horizontal angle in degrees
+    pub fn intensity(&self, vertical: f32, horizontal: f32) -> f32 {
+        let (v0, v1) = (self.vertical[0], self.vertical[self.vertical.len() - 1]);
+        if vertical < v0 || vertical > v1 {
+            return 0.0;
+        }
+
+        // The horizontal range tells the symmetry of the distribution
+        let last = self.horizontal[self.horizontal.len() - 1];
+        let mut h = horizontal.rem_euclid(360.0);
+        if last <= 0.0 {
+            h = 0.0;
+        } else if last <= 90.0 {
+            if h > 180.0 { h = 360.0 - h; }
+            if h > 90.0 { h = 180.0 - h; }
+        } else if last <= 180.0 && h > 180.0 {
+            h = 360.0 - h;
+        }
+
+        let (hi, hf) = bracket(&self.horizontal, h);
+        let (vi, vf) = bracket(&self.vertical, vertical);
+        let at = |h: usize, v: usize| self.candela[h.min(self.horizontal.len() - 1)][v.min(self.vertical.len() - 1)];
+        let lower = at(hi, vi) * (1.0 - vf) + at(hi, vi + 1) * vf;
+        let upper = at(hi + 1, vi) * (1.0 - vf) + at(hi + 1, vi + 1) * vf;
+        lower * (1.0 - hf) + upper * hf
+    }
+
+    // Luminous flux in lumen, the integral of the intensity over the sphere
+    pub fn lumens(&self) -> f32 {
+        let (rows, columns) = (90, 180);
+        let d_theta = f32::consts::PI / rows as f32;
+        let d_phi = 2.0 * f32::consts::PI / columns as f32;
+        let mut total = 0.0;
+        for i in 0..rows {
+            let theta = (i as f32 + 0.5) * d_theta;
+            for j in 0..columns {
+                let phi = (j as f32 + 0.5) * d_phi;
+                total += self.intensity(theta.to_degrees(), phi.to_degrees()) * theta.sin() * d_theta * d_phi;
+            }
+        }
+        total
+    }
+}
+
+// Index of the interval of the sorted angles that contains x and the
+// position inside it
+fn bracket(angles: &[f32], x: f32) -> (usize, f32) {
+    if angles.len() < 2 {
+        return (0, 0.0);
+    }
+    let i = angles.partition_point(|&a| a <= x).clamp(1, angles.len() - 1) - 1;
+    let width = angles[i + 1] - angles[i];
+    let f = if width > 0.0 { (x - angles[i]) / width } else { 0.0 };
+    (i, f.clamp(0.0, 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rotationally symmetric type C profile, 100 cd straight down fading to
+    // 0 at the horizon, with a multiplier of 2
+    const PROFILE: &str = "IESNA:LM-63-2002
+[TEST] small test profile
+TILT=NONE
+1 1000 2 3 1 1 1 0 0 0
+1 1 50
+0 45 90
+0
+100 50 0
+";
+
+    #[test]
+    fn parses_type_c_profile() {
+        let profile = IesProfile::parse(PROFILE).unwrap();
+        assert_eq!(profile.vertical, vec![0.0, 45.0, 90.0]);
+        assert_eq!(profile.horizontal, vec![0.0]);
+        assert_eq!(profile.intensity(0.0, 0.0), 200.0);
+        assert_eq!(profile.intensity(22.5, 0.0), 150.0);
+        // A single horizontal angle is the same all the way around
+        assert_eq!(profile.intensity(45.0, 137.0), 100.0);
+        assert_eq!(profile.intensity(120.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn rejects_counts_that_dont_fit_the_data() {
+        let huge = PROFILE.replace("1 1000 2 3 1 1", "1 1000 2 3000000000 3000000000 1");
+        assert!(IesProfile::parse(&huge).is_err());
+        let truncated = PROFILE.replace("100 50 0", "100 50");
+        assert!(IesProfile::parse(&truncated).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use nalgebra::Vector3;
+use rand::{thread_rng, Rng};
+use std::{f32, sync::Arc};
+
+use crate::hittable::HitRecord;
+use crate::material::{Lambertian, Material};
+use crate::microfacet::{Frame, Ggx, eval_dielectric, fresnel_dielectric, sample_dielectric};
+use crate::ray::{Ray, RayKind};
+use crate::texture::{ConstantTex, Texture};
+use crate::vec::{vec, vec_one, vec_zero};
+
+// Dielectric coat (varnish, lacquer, clearcoat) over any other material.
+//
+// Light is traced through the layer stack stochastically: refract into the
+// coat, attenuate by the coat absorption, scatter off the base, attenuate again
+// and then either leave through the coat or get reflected back down by it, as
+// often as needed. Every event is sampled with its own probability, so no energy
+// is created or lost apart from absorption and paths that are cut off after
+// MAX_INTERNAL_BOUNCES.
+pub struct Layered {
+    pub base: Arc<dyn Material>,
+    pub ior: f32,
+    pub roughness: Arc<dyn Texture>,
+    // Transmittance of the coat at normal incidence for a thickness of 1
+    pub color: Vector3<f32>,
+    pub thickness: f32,
+}
+
+const MAX_INTERNAL_BOUNCES: u32 = 16;
+
+impl Layered {
+    pub fn new(base: Arc<dyn Material>) -> Self {
+        Layered {
+            base,
+            ..Default::default()
+        }
+    }
+
+    // Fraction of light left after crossing the coat in direction `w`
+    fn transmittance(&self, w: Vector3<f32>) -> Vector3<f32> {
+        if self.thickness <= 0.0 {
+            return vec_one();
+        }
+        let distance = self.thickness / w.z.abs().max(1e-4);
+        self.color.map(|c| c.max(1e-6).powf(distance))
+    }
+}
+
+impl Default for Layered {
+    fn default() -> Self {
+        Layered {
+            base: Arc::new(Lambertian { albedo: ConstantTex::new_arc(vec(0.5, 0.5, 0.5)) }),
+            ior: 1.5,
+            roughness: ConstantTex::new_arc(vec_zero()),
+            color: vec_one(),
+            thickness: 0.0,
+        }
+    }
+}
+
+// Mirrors a local direction to the frame of the underside of the coat
+fn flip(w: Vector3<f32>) -> Vector3<f32> {
+    vec(w.x, w.y, -w.z)
+}
+
+impl Material for Layered {
+    fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        let distribution = Ggx::from_roughness(self.roughness.value(hit.uv, hit.p).x, 0.0);
+        let mut rng = thread_rng();
+        // Diffuse if any base bounce was, specular if every event was
+        let mut diffuse = false;
+        let mut specular = distribution.is_smooth();
+        let kind = |diffuse: bool, specular: bool| {
+            if diffuse { RayKind::Diffuse } else if specular { RayKind::Specular } else { RayKind::Glossy }
+        };
+
+        // Top of the coat, seen from outside
+        let (mut w, mut weight) = sample_dielectric(wo, self.ior, &distribution, &mut rng)
+            .map(|(wi, weight)| (wi, vec_one() * weight))?;
+        if w.z > 0.0 {
+            return Some((Ray::new(hit.p, frame.to_world(w)).with_kind(kind(diffuse, specular)), weight));
+        }
+
+        for _ in 0..MAX_INTERNAL_BOUNCES {
+            // Down through the coat to the base
+            weight = weight.component_mul(&self.transmittance(w));
+            let (base_ray, attenuation) = self.base.scatter(&Ray::new(hit.p, frame.to_world(w)), hit)?;
+            w = frame.to_local(base_ray.direction().normalize());
+            if w.z <= 0.0 {
+                return None;
+            }
+            diffuse |= base_ray.kind == RayKind::Diffuse;
+            specular &= base_ray.kind == RayKind::Specular;
+            weight = weight.component_mul(&attenuation);
+
+            // Back up to the top of the coat, seen from inside
+            weight = weight.component_mul(&self.transmittance(w));
+            let (wi, interface_weight) = sample_dielectric(flip(-w), 1.0 / self.ior, &distribution, &mut rng)?;
+            weight *= interface_weight;
+            w = flip(wi);
+            if w.z > 0.0 {
+                return Some((Ray::new(hit.p, frame.to_world(w)).with_kind(kind(diffuse, specular)), weight));
+            }
+
+            // Russian roulette on long internal paths
+            let survival = weight.max().min(1.0);
+            if rng.gen::<f32>() >= survival {
+                return None;
+            }
+            weight /= survival;
+        }
+        None
+    }
+
+    // Single sample estimate of the layer stack in the spirit of pbrt-v4's
+    // LayeredBxDF: reflection off the top, plus a random walk inside the coat
+    // that connects every base bounce to wi through an exit direction sampled
+    // from the wi side. Delta base lobes, which can't be connected, are counted
+    // when the walk leaves through the rough coat towards wi instead. The pdf
+    // is only an approximation, good enough for MIS weights.
+    fn eval(&self, ray: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        let wi = frame.to_local(direction.normalize());
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Some((vec_zero(), 0.0));
+        }
+        let distribution = Ggx::from_roughness(self.roughness.value(hit.uv, hit.p).x, 0.0);
+        let mut rng = thread_rng();
+
+        let (top, top_pdf) = eval_dielectric(wo, wi, self.ior, &distribution);
+        let mut value = vec_one() * top;
+        let fresnel = fresnel_dielectric(wo.z, self.ior);
+        let pdf = 0.9 * (top_pdf + (1.0 - fresnel) * wi.z / f32::consts::PI) + 0.1 / (4.0 * f32::consts::PI);
+
+        // Into the coat from wo, and out of it towards wi. The exit is sampled
+        // the other way around than light flows, which adds a factor of ior^2
+        let entry = sample_dielectric(wo, self.ior, &distribution, &mut rng).filter(|(w, _)| w.z < 0.0);
+        let exit = sample_dielectric(wi, self.ior, &distribution, &mut rng).filter(|(w, _)| w.z < 0.0);
+        let (mut w, entry_weight) = match entry {
+            Some(entry) => entry,
+            None => return Some((value, pdf)),
+        };
+        let mut weight = vec_one() * entry_weight;
+
+        for _ in 0..MAX_INTERNAL_BOUNCES {
+            weight = weight.component_mul(&self.transmittance(w));
+            let down = Ray::new(hit.p, frame.to_world(w));
+
+            // Whether the base can be evaluated doesn't depend on the exit
+            let up = exit.map_or(vec(0.0, 0.0, 1.0), |(exit_w, _)| -exit_w);
+            let base_value = self.base.eval(&down, hit, frame.to_world(up));
+            let connected = base_value.is_some();
+            if let (Some((f, _)), Some((exit_w, exit_weight))) = (base_value, exit) {
+                let exit_value = exit_weight / (self.ior * self.ior) / exit_w.z.abs() * wi.z;
+                value += weight.component_mul(&f).component_mul(&self.transmittance(exit_w)) * exit_value;
+            }
+
+            let Some((base_ray, attenuation)) = self.base.scatter(&down, hit) else { break };
+            w = frame.to_local(base_ray.direction().normalize());
+            if w.z <= 0.0 {
+                break;
+            }
+            weight = weight.component_mul(&attenuation).component_mul(&self.transmittance(w));
+            if !connected || base_ray.kind == RayKind::Specular {
+                let (exit_value, _) = eval_dielectric(flip(-w), flip(wi), 1.0 / self.ior, &distribution);
+                value += weight * exit_value;
+            }
+
+            // Only internal reflection continues, leaving was accounted for above
+            let Some((wr, interface_weight)) = sample_dielectric(flip(-w), 1.0 / self.ior, &distribution, &mut rng) else { break };
+            if wr.z < 0.0 {
+                break;
+            }
+            weight *= interface_weight;
+            w = flip(wr);
+
+            let survival = weight.max().min(1.0);
+            if rng.gen::<f32>() >= survival {
+                break;
+            }
+            weight /= survival;
+        }
+        Some((value, pdf))
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &HitRecord) -> Vector3<f32> {
+        // Emission of the base as seen through the coat, ignoring the interface
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        self.base.emitted(ray, hit).component_mul(&self.transmittance(wo))
+    }
+}
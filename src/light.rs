@@ -0,0 +1,771 @@
+use nalgebra::{Rotation3, Vector2, Vector3};
+use std::f32;
+use std::sync::Arc;
+
+use crate::aabb::AABB;
+use crate::hittable::{HitRecord, Hittable, Transform};
+use crate::ies::IesProfile;
+use crate::light_bvh::{DirectionCone, LightBounds, LightBvh};
+use crate::material::Material;
+use crate::microfacet::Frame;
+use crate::ray::Ray;
+use crate::spectrum;
+use crate::texture::Texture;
+use crate::vec::{deg_to_rad, luminance, vec2, vec3, vec_one, vec_zero};
+
+// Lights that can be sampled directly from a shading point (next event
+// estimation). Area lights are part of the scene geometry as well, so paths
+// can also find them by chance, the integrator weights both strategies with
+// multiple importance sampling.
+pub trait Light: Sync + Send {
+    // Samples a direction towards the light from p, u is uniform in [0, 1)^2
+    fn sample(&self, p: Vector3<f32>, u: Vector2<f32>) -> Option<LightSample>;
+    // Solid angle density `sample` has for the direction from p to a point
+    // where a path hit the light
+    fn pdf(&self, p: Vector3<f32>, hit: &HitRecord) -> f32;
+    // Emitted power, used to pick between lights. Lights at infinity shine on
+    // a disk of the scene radius.
+    fn power(&self, scene_radius: f32) -> f32;
+    // Bit mask of the groups the light is in, it only lights objects that
+    // share a group with it (light linking, see Visibility)
+    fn light_groups(&self) -> u32;
+    // Where and in which directions the light emits, for the light BVH.
+    // None for lights at infinity.
+    fn bounds(&self) -> Option<LightBounds>;
+}
+
+pub const ALL_LIGHT_GROUPS: u32 = u32::MAX;
+
+pub struct LightSample {
+    // Unit direction towards the light
+    pub direction: Vector3<f32>,
+    // Distance along direction that has to be unoccluded
+    pub distance: f32,
+    pub radiance: Vector3<f32>,
+    // Solid angle density, 1 for delta lights
+    pub pdf: f32,
+    // Lights paths can't hit, their samples don't need MIS
+    pub delta: bool,
+}
+
+// Power heuristic with beta = 2 (Veach 1997)
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+// Picks the light to sample for a shading point. Lights with bounds go into a
+// light BVH that prefers the ones close to and facing the point, lights at
+// infinity are picked by power and the two sets by their total power.
+#[derive(Default)]
+pub struct LightSampler {
+    bvh: LightBvh,
+    infinite: Vec<Arc<dyn Light>>,
+    cdf: Vec<f32>,
+    infinite_power: f32,
+    scene_radius: f32,
+}
+
+impl LightSampler {
+    pub fn new(lights: Vec<Arc<dyn Light>>, scene_radius: f32) -> Self {
+        let (infinite, bounded): (Vec<_>, Vec<_>) = lights.into_iter().partition(|light| light.bounds().is_none());
+        let mut cdf = Vec::with_capacity(infinite.len());
+        let mut infinite_power = 0.0;
+        for light in infinite.iter() {
+            infinite_power += light.power(scene_radius).max(0.0);
+            cdf.push(infinite_power);
+        }
+        LightSampler { bvh: LightBvh::new(bounded), infinite, cdf, infinite_power, scene_radius }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.infinite_power <= 0.0 && self.bvh.is_empty()
+    }
+
+    fn infinite_probability(&self) -> f32 {
+        let total = self.infinite_power + self.bvh.phi();
+        if total > 0.0 { self.infinite_power / total } else { 0.0 }
+    }
+
+    // A light and the probability it was picked with for a point with
+    // normal n, zero in media. u is uniform in [0, 1).
+    pub fn sample(&self, p: Vector3<f32>, n: Vector3<f32>, u: f32) -> Option<(&Arc<dyn Light>, f32)> {
+        if self.is_empty() {
+            return None;
+        }
+        let p_infinite = self.infinite_probability();
+        if u < p_infinite {
+            let target = u / p_infinite * self.infinite_power;
+            let i = self.cdf.partition_point(|&c| c <= target).min(self.infinite.len() - 1);
+            let light = &self.infinite[i];
+            Some((light, p_infinite * light.power(self.scene_radius).max(0.0) / self.infinite_power))
+        } else {
+            let u = ((u - p_infinite) / (1.0 - p_infinite)).min(1.0 - f32::EPSILON);
+            let (light, pmf) = self.bvh.sample(p, n, u)?;
+            Some((light, (1.0 - p_infinite) * pmf))
+        }
+    }
+
+    pub fn pmf(&self, p: Vector3<f32>, n: Vector3<f32>, light: &dyn Light) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let p_infinite = self.infinite_probability();
+        if light.bounds().is_none() {
+            p_infinite * light.power(self.scene_radius).max(0.0) / self.infinite_power
+        } else {
+            (1.0 - p_infinite) * self.bvh.pmf(p, n, light)
+        }
+    }
+}
+
+// Surfaces area lights can be placed on. Points are found through the uv
+// parameterization the surface also reports in its hits, so textured emission
+// can be importance sampled by uv.
+// A point on a shape sampled by solid angle, pdf is per steradian
+pub struct ShapeSample {
+    pub point: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub uv: Vector2<f32>,
+    pub pdf: f32,
+}
+
+pub trait Shape: Hittable {
+    // Point, outward normal and the area per unit of uv, dA / (du dv)
+    fn point(&self, uv: Vector2<f32>) -> (Vector3<f32>, Vector3<f32>, f32);
+    fn area(&self) -> f32;
+    // Directions of the outward normals
+    fn normal_bounds(&self) -> DirectionCone;
+    // Shapes that can sample the part visible from p by solid angle do so
+    // here. Otherwise, or where p is inside, lights sample the area.
+    fn sample_solid_angle(&self, _p: Vector3<f32>, _u: Vector2<f32>) -> Option<ShapeSample> {
+        None
+    }
+    // Density of sample_solid_angle returning `point`
+    fn pdf_solid_angle(&self, _p: Vector3<f32>, _point: Vector3<f32>) -> Option<f32> {
+        None
+    }
+}
+
+// A shape moved and rotated like Transform moves objects. Area lights have to
+// be transformed this way, wrapping AreaLight::object in a Transform would
+// move the geometry but not the points the light samples.
+pub struct TransformedShape {
+    shape: Arc<dyn Shape>,
+    transform: Transform,
+}
+
+impl TransformedShape {
+    pub fn new(shape: impl Shape + 'static, offset: Vector3<f32>, rotation_deg: Vector3<f32>) -> Self {
+        let shape: Arc<dyn Shape> = Arc::new(shape);
+        let transform = Transform::new_b(shape.clone(), offset, rotation_deg);
+        TransformedShape { shape, transform }
+    }
+
+    fn to_world(&self, p: Vector3<f32>) -> Vector3<f32> {
+        self.transform.rotation * p + self.transform.offset
+    }
+
+    fn to_local(&self, p: Vector3<f32>) -> Vector3<f32> {
+        self.transform.rotation.inverse() * (p - self.transform.offset)
+    }
+}
+
+impl Hittable for TransformedShape {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        self.transform.hit(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Option<AABB> {
+        self.transform.bounding_box()
+    }
+}
+
+// Rotations and offsets keep areas and solid angles, only points and
+// directions change
+impl Shape for TransformedShape {
+    fn point(&self, uv: Vector2<f32>) -> (Vector3<f32>, Vector3<f32>, f32) {
+        let (p, normal, jacobian) = self.shape.point(uv);
+        (self.to_world(p), self.transform.rotation * normal, jacobian)
+    }
+
+    fn area(&self) -> f32 {
+        self.shape.area()
+    }
+
+    fn normal_bounds(&self) -> DirectionCone {
+        let normals = self.shape.normal_bounds();
+        DirectionCone { w: self.transform.rotation * normals.w, ..normals }
+    }
+
+    fn sample_solid_angle(&self, p: Vector3<f32>, u: Vector2<f32>) -> Option<ShapeSample> {
+        let sample = self.shape.sample_solid_angle(self.to_local(p), u)?;
+        Some(ShapeSample {
+            point: self.to_world(sample.point),
+            normal: self.transform.rotation * sample.normal,
+            ..sample
+        })
+    }
+
+    fn pdf_solid_angle(&self, p: Vector3<f32>, point: Vector3<f32>) -> Option<f32> {
+        self.shape.pdf_solid_angle(self.to_local(p), self.to_local(point))
+    }
+}
+
+// Scene radiance of 1 is 1000 nits (1 kcd/m^2), like the sky model. Power
+// is converted with 683 lm/W, so watts and nits stay proportional for any
+// color, and scene units are taken to be meters.
+pub const NITS_PER_UNIT: f32 = 1000.0;
+const LUMENS_PER_WATT: f32 = 683.0;
+const DISTRIBUTION_RESOLUTION: usize = 64;
+
+// Color of a blackbody with unit luminance, for tinting lights
+pub fn blackbody_color(kelvin: f32) -> Vector3<f32> {
+    let color = spectrum::blackbody(kelvin);
+    if luminance(color) > 0.0 { color / luminance(color) } else { vec_zero() }
+}
+
+// Radiant intensity in scene units from candela and a color
+fn intensity(color: Vector3<f32>, candela: f32) -> Vector3<f32> {
+    let l = luminance(color);
+    if l > 0.0 { color / l * (candela / NITS_PER_UNIT) } else { vec_zero() }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Intensity {
+    // The emission texture is the radiance
+    Texture,
+    // Average luminance of the surface
+    Nits(f32),
+    // Total emitted power
+    Watts(f32),
+}
+
+// Diffuse area light on a rectangle or sphere. The emission texture gives the
+// color and pattern, the intensity can be set in physical units and a color
+// temperature tints it like a blackbody. Textured emission is importance
+// sampled so bright spots get most of the samples, except on shapes that
+// sample the solid angle they cover, like spheres seen from outside.
+pub struct AreaLight {
+    shape: Arc<dyn Shape>,
+    emission: Arc<dyn Texture>,
+    intensity: Intensity,
+    tint: Vector3<f32>,
+    two_sided: bool,
+    flipped: bool,
+    light_groups: u32,
+    distribution: Distribution2D,
+    // Area weighted average of the emission texture
+    average_emission: Vector3<f32>,
+    scale: f32,
+}
+
+impl AreaLight {
+    // One sided, emitting on the side the shape's normal points to
+    pub fn new(shape: impl Shape + 'static, emission: Arc<dyn Texture>) -> Self {
+        let mut average_emission = vec_zero();
+        let mut total_jacobian = 0.0;
+        let distribution = Distribution2D::new(DISTRIBUTION_RESOLUTION, |uv| {
+            let (p, _, jacobian) = shape.point(uv);
+            let value = emission.value(uv, p);
+            average_emission += value * jacobian;
+            total_jacobian += jacobian;
+            luminance(value).max(0.0) * jacobian
+        });
+        let mut light = AreaLight {
+            shape: Arc::new(shape),
+            emission,
+            intensity: Intensity::Texture,
+            tint: vec_one(),
+            two_sided: false,
+            flipped: false,
+            light_groups: ALL_LIGHT_GROUPS,
+            distribution,
+            average_emission: if total_jacobian > 0.0 { average_emission / total_jacobian } else { vec_zero() },
+            scale: 1.0,
+        };
+        light.update_scale();
+        light
+    }
+
+    pub fn nits(mut self, nits: f32) -> Self {
+        self.intensity = Intensity::Nits(nits);
+        self.update_scale();
+        self
+    }
+
+    pub fn watts(mut self, watts: f32) -> Self {
+        self.intensity = Intensity::Watts(watts);
+        self.update_scale();
+        self
+    }
+
+    pub fn temperature(mut self, kelvin: f32) -> Self {
+        self.tint = blackbody_color(kelvin);
+        self.update_scale();
+        self
+    }
+
+    pub fn two_sided(mut self, two_sided: bool) -> Self {
+        self.two_sided = two_sided;
+        self.update_scale();
+        self
+    }
+
+    // Emit on the side opposite to the normal
+    pub fn flipped(mut self, flipped: bool) -> Self {
+        self.flipped = flipped;
+        self
+    }
+
+    pub fn light_groups(mut self, light_groups: u32) -> Self {
+        self.light_groups = light_groups;
+        self
+    }
+
+    // The light as scene geometry, both this and the light go into the scene.
+    // Use a TransformedShape to place it, not a Transform around this.
+    pub fn object(self: &Arc<Self>) -> Arc<dyn Hittable> {
+        Arc::new(AreaLightObject {
+            light: Arc::clone(self),
+            surface: Arc::new(AreaLightSurface { light: Arc::clone(self) }),
+        })
+    }
+
+    fn sides(&self) -> f32 {
+        if self.two_sided { 2.0 } else { 1.0 }
+    }
+
+    fn update_scale(&mut self) {
+        let average = luminance(self.average_emission.component_mul(&self.tint));
+        let nits = match self.intensity {
+            Intensity::Texture => {
+                self.scale = 1.0;
+                return;
+            }
+            Intensity::Nits(nits) => nits,
+            // Power of a diffuse emitter is pi * radiance * area per side
+            Intensity::Watts(watts) => watts * LUMENS_PER_WATT / (f32::consts::PI * self.shape.area() * self.sides()),
+        };
+        self.scale = if average > 0.0 { nits / NITS_PER_UNIT / average } else { 0.0 };
+    }
+
+    fn radiance(&self, uv: Vector2<f32>, p: Vector3<f32>, front_face: bool) -> Vector3<f32> {
+        if !self.two_sided && front_face == self.flipped {
+            return vec_zero();
+        }
+        self.emission.value(uv, p).component_mul(&self.tint) * self.scale
+    }
+}
+
+impl Light for AreaLight {
+    fn sample(&self, p: Vector3<f32>, u: Vector2<f32>) -> Option<LightSample> {
+        if let Some(ShapeSample { point, normal, uv, pdf }) = self.shape.sample_solid_angle(p, u) {
+            let to_light = point - p;
+            let distance = to_light.magnitude();
+            let direction = to_light / distance;
+            let radiance = self.radiance(uv, point, normal.dot(&direction) < 0.0);
+            if distance <= 0.0 || radiance == vec_zero() {
+                return None;
+            }
+            return Some(LightSample { direction, distance, radiance, pdf, delta: false });
+        }
+
+        let (uv, uv_pdf) = self.distribution.sample(u)?;
+        let (point, normal, jacobian) = self.shape.point(uv);
+        let to_light = point - p;
+        let distance = to_light.magnitude();
+        if jacobian <= 0.0 || distance <= 0.0 {
+            return None;
+        }
+        let direction = to_light / distance;
+        let cos_light = normal.dot(&direction);
+        if cos_light == 0.0 {
+            return None;
+        }
+        let radiance = self.radiance(uv, point, cos_light < 0.0);
+        if radiance == vec_zero() {
+            return None;
+        }
+        Some(LightSample {
+            direction,
+            distance,
+            radiance,
+            pdf: uv_pdf / jacobian * distance * distance / cos_light.abs(),
+            delta: false,
+        })
+    }
+
+    fn pdf(&self, p: Vector3<f32>, hit: &HitRecord) -> f32 {
+        if let Some(pdf) = self.shape.pdf_solid_angle(p, hit.p) {
+            return pdf;
+        }
+        let (_, _, jacobian) = self.shape.point(hit.uv);
+        let to_light = hit.p - p;
+        let distance_squared = to_light.magnitude_squared();
+        let cos_light = hit.normal.dot(&to_light.normalize()).abs();
+        if jacobian <= 0.0 || cos_light <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(hit.uv) / jacobian * distance_squared / cos_light
+    }
+
+    fn power(&self, _scene_radius: f32) -> f32 {
+        luminance(self.average_emission.component_mul(&self.tint))
+            * self.scale * f32::consts::PI * self.shape.area() * self.sides()
+    }
+
+    fn light_groups(&self) -> u32 {
+        self.light_groups
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let mut normals = self.shape.normal_bounds();
+        if self.flipped {
+            normals.w = -normals.w;
+        }
+        Some(LightBounds {
+            bbox: self.shape.bounding_box()?,
+            phi: self.power(0.0),
+            normals,
+            cos_theta_e: 0.0,
+            two_sided: self.two_sided,
+        })
+    }
+}
+
+struct AreaLightObject {
+    light: Arc<AreaLight>,
+    surface: Arc<dyn Material>,
+}
+
+impl Hittable for AreaLightObject {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut hit = self.light.shape.hit(ray, t_min, t_max)?;
+        hit.material = Arc::clone(&self.surface);
+        Some(hit)
+    }
+
+    fn bounding_box(&self) -> Option<AABB> {
+        self.light.shape.bounding_box()
+    }
+}
+
+struct AreaLightSurface {
+    light: Arc<AreaLight>,
+}
+
+impl Material for AreaLightSurface {
+    fn scatter(&self, _ray: &Ray, _hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
+        None
+    }
+
+    fn emitted(&self, _ray: &Ray, hit: &HitRecord) -> Vector3<f32> {
+        self.light.radiance(hit.uv, hit.p, hit.front_face)
+    }
+
+    fn area_light(&self) -> Option<&dyn Light> {
+        Some(&*self.light)
+    }
+}
+
+// Piecewise constant density over [0, 1]^2 on a regular grid, sampled by
+// picking a row from the marginal and then a cell from that row
+struct Distribution2D {
+    resolution: usize,
+    func: Vec<f32>,
+    row_cdfs: Vec<Vec<f32>>,
+    marginal_cdf: Vec<f32>,
+    average: f32,
+}
+
+fn build_cdf(values: &[f32]) -> Vec<f32> {
+    let mut cdf = Vec::with_capacity(values.len() + 1);
+    let mut sum = 0.0;
+    cdf.push(0.0);
+    for v in values {
+        sum += v;
+        cdf.push(sum);
+    }
+    cdf
+}
+
+// Index of the segment of the cdf u falls into and the position inside it
+fn sample_cdf(cdf: &[f32], u: f32) -> (usize, f32) {
+    let total = cdf[cdf.len() - 1];
+    let target = u * total;
+    let i = cdf.partition_point(|&c| c <= target).clamp(1, cdf.len() - 1) - 1;
+    let width = cdf[i + 1] - cdf[i];
+    let offset = if width > 0.0 { (target - cdf[i]) / width } else { 0.5 };
+    (i, offset.clamp(0.0, 1.0))
+}
+
+impl Distribution2D {
+    // f is evaluated at the cell centers, v picks the row
+    fn new(resolution: usize, mut f: impl FnMut(Vector2<f32>) -> f32) -> Self {
+        let n = resolution as f32;
+        let mut func = Vec::with_capacity(resolution * resolution);
+        for j in 0..resolution {
+            for i in 0..resolution {
+                func.push(f(vec2((i as f32 + 0.5) / n, (j as f32 + 0.5) / n)));
+            }
+        }
+        let row_cdfs: Vec<Vec<f32>> = func.chunks(resolution).map(build_cdf).collect();
+        let marginal_cdf = build_cdf(&row_cdfs.iter().map(|cdf| cdf[resolution]).collect::<Vec<f32>>());
+        let average = marginal_cdf[resolution] / (n * n);
+        Distribution2D { resolution, func, row_cdfs, marginal_cdf, average }
+    }
+
+    // uv and its density
+    fn sample(&self, u: Vector2<f32>) -> Option<(Vector2<f32>, f32)> {
+        if self.average <= 0.0 {
+            return None;
+        }
+        let n = self.resolution as f32;
+        let (j, dv) = sample_cdf(&self.marginal_cdf, u.y);
+        let (i, du) = sample_cdf(&self.row_cdfs[j], u.x);
+        let uv = vec2((i as f32 + du) / n, (j as f32 + dv) / n);
+        Some((uv, self.func[j * self.resolution + i] / self.average))
+    }
+
+    fn pdf(&self, uv: Vector2<f32>) -> f32 {
+        if self.average <= 0.0 {
+            return 0.0;
+        }
+        let n = self.resolution as f32;
+        let i = ((uv.x * n) as usize).min(self.resolution - 1);
+        let j = ((uv.y * n) as usize).min(self.resolution - 1);
+        self.func[j * self.resolution + i] / self.average
+    }
+}
+
+// Point and spot lights are infinitely small, so paths can never hit them and
+// they only light what Material::eval can evaluate: diffuse and rough GGX
+// lobes and volumes. Perfectly smooth mirrors and glass and the fuzzy Metal
+// stay dark, as does every delta lobe of a material that mixes lobes. The
+// intensity is in scene units, 1 for 1000 candela, see the constructors.
+pub struct PointLight {
+    pub position: Vector3<f32>,
+    pub intensity: Vector3<f32>,
+    pub light_groups: u32,
+}
+
+impl PointLight {
+    // Luminous intensity in candela, the color only sets the hue
+    pub fn new(position: Vector3<f32>, color: Vector3<f32>, candela: f32) -> Self {
+        PointLight { position, intensity: intensity(color, candela), light_groups: ALL_LIGHT_GROUPS }
+    }
+}
+
+fn point_bounds(position: Vector3<f32>, phi: f32, normals: DirectionCone, cos_theta_e: f32) -> LightBounds {
+    LightBounds { bbox: AABB { min: position, max: position }, phi, normals, cos_theta_e, two_sided: false }
+}
+
+// Radiance arriving at p from a delta light at `position` with the given
+// intensity in that direction
+fn delta_sample(p: Vector3<f32>, position: Vector3<f32>, intensity: impl Fn(Vector3<f32>) -> Vector3<f32>) -> Option<LightSample> {
+    let to_light = position - p;
+    let distance = to_light.magnitude();
+    if distance <= 0.0 {
+        return None;
+    }
+    let direction = to_light / distance;
+    let radiance = intensity(-direction) / (distance * distance);
+    if radiance.max() <= 0.0 {
+        return None;
+    }
+    Some(LightSample { direction, distance, radiance, pdf: 1.0, delta: true })
+}
+
+impl Light for PointLight {
+    fn sample(&self, p: Vector3<f32>, _u: Vector2<f32>) -> Option<LightSample> {
+        delta_sample(p, self.position, |_| self.intensity)
+    }
+
+    fn pdf(&self, _p: Vector3<f32>, _hit: &HitRecord) -> f32 {
+        0.0
+    }
+
+    fn power(&self, _scene_radius: f32) -> f32 {
+        4.0 * f32::consts::PI * luminance(self.intensity)
+    }
+
+    fn light_groups(&self) -> u32 {
+        self.light_groups
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        Some(point_bounds(self.position, self.power(0.0), DirectionCone::entire_sphere(), 0.0))
+    }
+}
+
+// Point light limited to a cone, full intensity inside the inner angle and
+// a smooth falloff to zero at the outer angle. Angles are half angles.
+pub struct SpotLight {
+    pub position: Vector3<f32>,
+    pub direction: Vector3<f32>,
+    pub intensity: Vector3<f32>,
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+    pub light_groups: u32,
+}
+
+impl SpotLight {
+    pub fn new(position: Vector3<f32>, target: Vector3<f32>, color: Vector3<f32>, candela: f32, inner_deg: f32, outer_deg: f32) -> Self {
+        SpotLight {
+            position,
+            direction: (target - position).normalize(),
+            intensity: intensity(color, candela),
+            cos_inner: inner_deg.min(outer_deg).to_radians().cos(),
+            cos_outer: outer_deg.to_radians().cos(),
+            light_groups: ALL_LIGHT_GROUPS,
+        }
+    }
+
+    fn falloff(&self, direction: Vector3<f32>) -> f32 {
+        let cos_theta = direction.dot(&self.direction);
+        if cos_theta >= self.cos_inner {
+            return 1.0;
+        }
+        let t = ((cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, p: Vector3<f32>, _u: Vector2<f32>) -> Option<LightSample> {
+        delta_sample(p, self.position, |d| self.intensity * self.falloff(d))
+    }
+
+    fn pdf(&self, _p: Vector3<f32>, _hit: &HitRecord) -> f32 {
+        0.0
+    }
+
+    // The falloff is approximated as linear in cos theta
+    fn power(&self, _scene_radius: f32) -> f32 {
+        2.0 * f32::consts::PI * luminance(self.intensity) * (1.0 - 0.5 * (self.cos_inner + self.cos_outer))
+    }
+
+    fn light_groups(&self) -> u32 {
+        self.light_groups
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        // The falloff region is the spread around the inner cone
+        let cos_theta_e = (self.cos_outer.clamp(-1.0, 1.0).acos() - self.cos_inner.clamp(-1.0, 1.0).acos()).cos();
+        Some(point_bounds(self.position, self.power(0.0), DirectionCone::new(self.direction, self.cos_inner), cos_theta_e))
+    }
+}
+
+// Light from a luminaire with a measured IES distribution. The profile's
+// nadir points down (-y) and horizontal angle 0 along +x before rotating by
+// rotation_deg (Euler angles like Transform). scale multiplies the measured
+// candela, e.g. to dim the fixture.
+pub struct PhotometricLight {
+    pub position: Vector3<f32>,
+    pub rotation: Rotation3<f32>,
+    pub profile: IesProfile,
+    pub color: Vector3<f32>,
+    pub scale: f32,
+    pub light_groups: u32,
+}
+
+impl PhotometricLight {
+    pub fn new(position: Vector3<f32>, rotation_deg: Vector3<f32>, profile: IesProfile) -> Self {
+        PhotometricLight {
+            position,
+            rotation: Rotation3::from_euler_angles(deg_to_rad(rotation_deg.x), deg_to_rad(rotation_deg.y), deg_to_rad(rotation_deg.z)),
+            profile,
+            color: vec_one(),
+            scale: 1.0,
+            light_groups: ALL_LIGHT_GROUPS,
+        }
+    }
+
+    fn candela(&self, direction: Vector3<f32>) -> f32 {
+        let local = self.rotation.inverse() * direction;
+        let vertical = (-local.y).clamp(-1.0, 1.0).acos().to_degrees();
+        let horizontal = local.z.atan2(local.x).to_degrees();
+        self.profile.intensity(vertical, horizontal) * self.scale
+    }
+}
+
+impl Light for PhotometricLight {
+    fn sample(&self, p: Vector3<f32>, _u: Vector2<f32>) -> Option<LightSample> {
+        delta_sample(p, self.position, |d| intensity(self.color, self.candela(d)))
+    }
+
+    fn pdf(&self, _p: Vector3<f32>, _hit: &HitRecord) -> f32 {
+        0.0
+    }
+
+    fn power(&self, _scene_radius: f32) -> f32 {
+        self.profile.lumens() * self.scale / NITS_PER_UNIT
+    }
+
+    fn light_groups(&self) -> u32 {
+        self.light_groups
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        Some(point_bounds(self.position, self.power(0.0), DirectionCone::entire_sphere(), 0.0))
+    }
+}
+
+// Light from infinitely far away like the sun. With an angular radius the
+// light comes from a disk of directions and shadows get soft, it is still
+// only sampled and never hit, so it doesn't show up in reflections. The
+// irradiance is in scene units, 1 for 1000 lux on a surface facing the light.
+pub struct DirectionalLight {
+    // Towards the light
+    pub direction: Vector3<f32>,
+    pub irradiance: Vector3<f32>,
+    // Half angle in degrees, the sun's is 0.2665
+    pub angular_radius: f32,
+    pub light_groups: u32,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vector3<f32>, color: Vector3<f32>, lux: f32, angular_radius: f32) -> Self {
+        DirectionalLight { direction: direction.normalize(), irradiance: intensity(color, lux), angular_radius, light_groups: ALL_LIGHT_GROUPS }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _p: Vector3<f32>, u: Vector2<f32>) -> Option<LightSample> {
+        let direction = self.direction.normalize();
+        if self.angular_radius <= 0.0 {
+            return Some(LightSample { direction, distance: f32::MAX, radiance: self.irradiance, pdf: 1.0, delta: true });
+        }
+        // Uniform in the cone, the radiance is the irradiance spread over it
+        let cos_max = self.angular_radius.to_radians().cos();
+        let solid_angle = 2.0 * f32::consts::PI * (1.0 - cos_max);
+        let cos_theta = 1.0 - u.x * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * f32::consts::PI * u.y;
+        let local = vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        Some(LightSample {
+            direction: Frame::from_normal(direction).to_world(local),
+            distance: f32::MAX,
+            radiance: self.irradiance / solid_angle,
+            pdf: 1.0 / solid_angle,
+            delta: true,
+        })
+    }
+
+    fn pdf(&self, _p: Vector3<f32>, _hit: &HitRecord) -> f32 {
+        0.0
+    }
+
+    fn power(&self, scene_radius: f32) -> f32 {
+        luminance(self.irradiance) * f32::consts::PI * scene_radius * scene_radius
+    }
+
+    fn light_groups(&self) -> u32 {
+        self.light_groups
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        None
+    }
+}
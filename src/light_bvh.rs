@@ -0,0 +1,393 @@
+use nalgebra::{Rotation3, Unit, Vector3};
+use std::collections::HashMap;
+use std::f32;
+use std::sync::Arc;
+
+use crate::aabb::{surrounding_box, AABB};
+use crate::light::Light;
+
+// Tree over the lights for picking one per shading point roughly in
+// proportion to its contribution there (Conty Estevez and Kulla 2018,
+// "Importance Sampling of Many Lights with Adaptive Tree Splitting", as
+// refined in pbrt-v4). Every node bounds the position, power and emission
+// directions of its lights, a traversal picks a child with probability
+// proportional to the importance of its bounds until it reaches a leaf.
+
+// Directions within acos(cos_theta) of w, -1 is the entire sphere
+#[derive(Clone, Copy, Debug)]
+pub struct DirectionCone {
+    pub w: Vector3<f32>,
+    pub cos_theta: f32,
+}
+
+fn safe_acos(x: f32) -> f32 {
+    x.clamp(-1.0, 1.0).acos()
+}
+
+fn safe_sqrt(x: f32) -> f32 {
+    x.max(0.0).sqrt()
+}
+
+impl DirectionCone {
+    pub fn new(w: Vector3<f32>, cos_theta: f32) -> Self {
+        DirectionCone { w: w.normalize(), cos_theta }
+    }
+
+    pub fn entire_sphere() -> Self {
+        DirectionCone { w: Vector3::new(0.0, 0.0, 1.0), cos_theta: -1.0 }
+    }
+
+    // Smallest cone around both, the axis is rotated from a's towards b's
+    fn union(a: &DirectionCone, b: &DirectionCone) -> DirectionCone {
+        let theta_a = safe_acos(a.cos_theta);
+        let theta_b = safe_acos(b.cos_theta);
+        let theta_d = safe_acos(a.w.dot(&b.w));
+        if (theta_d + theta_b).min(f32::consts::PI) <= theta_a {
+            return *a;
+        }
+        if (theta_d + theta_a).min(f32::consts::PI) <= theta_b {
+            return *b;
+        }
+        let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+        if theta_o >= f32::consts::PI {
+            return DirectionCone::entire_sphere();
+        }
+        let axis = match Unit::try_new(a.w.cross(&b.w), 1e-12) {
+            Some(axis) => axis,
+            None => return DirectionCone::entire_sphere(),
+        };
+        let w = Rotation3::from_axis_angle(&axis, theta_o - theta_a) * a.w;
+        DirectionCone::new(w, theta_o.cos())
+    }
+
+    // Directions from p to points in the box
+    fn bound_subtended(bbox: &AABB, p: Vector3<f32>) -> DirectionCone {
+        let center = (bbox.min + bbox.max) / 2.0;
+        let radius = (bbox.max - bbox.min).magnitude() / 2.0;
+        let distance_squared = (p - center).magnitude_squared();
+        if distance_squared <= radius * radius {
+            return DirectionCone::entire_sphere();
+        }
+        let sin2_theta = radius * radius / distance_squared;
+        DirectionCone::new(center - p, safe_sqrt(1.0 - sin2_theta))
+    }
+}
+
+// Bounds of one or more emitters. `normals` holds the surface normals (the
+// main emission directions), light leaves at up to acos(cos_theta_e) from
+// them, e.g. pi / 2 for diffuse surfaces. phi is the emitted power.
+#[derive(Clone, Copy, Debug)]
+pub struct LightBounds {
+    pub bbox: AABB,
+    pub phi: f32,
+    pub normals: DirectionCone,
+    pub cos_theta_e: f32,
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    fn centroid(&self) -> Vector3<f32> {
+        (self.bbox.min + self.bbox.max) / 2.0
+    }
+
+    fn union(a: &LightBounds, b: &LightBounds) -> LightBounds {
+        if a.phi <= 0.0 {
+            return *b;
+        }
+        if b.phi <= 0.0 {
+            return *a;
+        }
+        LightBounds {
+            bbox: surrounding_box(a.bbox, b.bbox),
+            phi: a.phi + b.phi,
+            normals: DirectionCone::union(&a.normals, &b.normals),
+            cos_theta_e: a.cos_theta_e.min(b.cos_theta_e),
+            two_sided: a.two_sided || b.two_sided,
+        }
+    }
+
+    // Conservative estimate of the light arriving at p from these lights,
+    // for a surface with normal n or a medium when n is zero
+    pub fn importance(&self, p: Vector3<f32>, n: Vector3<f32>) -> f32 {
+        // cos(max(0, a - b)) and sin(max(0, a - b)) from sines and cosines
+        let cos_sub_clamped = |sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32| {
+            if cos_a > cos_b { 1.0 } else { cos_a * cos_b + sin_a * sin_b }
+        };
+        let sin_sub_clamped = |sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32| {
+            if cos_a > cos_b { 0.0 } else { sin_a * cos_b - cos_a * sin_b }
+        };
+
+        let center = self.centroid();
+        // Clamped so points inside the bounds don't get an unbounded importance
+        let distance_squared = (p - center).magnitude_squared().max((self.bbox.max - self.bbox.min).magnitude() / 2.0);
+        let wi = (p - center).try_normalize(1e-12).unwrap_or(self.normals.w);
+
+        // Angle from the normals to p, minus the spread of the normals and of
+        // the bounds as seen from p
+        let mut cos_theta_w = self.normals.w.dot(&wi);
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+        let cos_theta_b = DirectionCone::bound_subtended(&self.bbox, p).cos_theta;
+        let sin_theta_b = safe_sqrt(1.0 - cos_theta_b * cos_theta_b);
+        let cos_theta_o = self.normals.cos_theta;
+        let sin_theta_o = safe_sqrt(1.0 - cos_theta_o * cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.phi * cos_theta_p / distance_squared;
+        if n != Vector3::zeros() {
+            let cos_theta_i = wi.dot(&n.normalize()).abs();
+            let sin_theta_i = safe_sqrt(1.0 - cos_theta_i * cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+        importance.max(0.0)
+    }
+}
+
+struct LightNode {
+    bounds: LightBounds,
+    // Light index for leaves, index of the second child otherwise, the first
+    // child follows its parent
+    index: usize,
+    is_leaf: bool,
+}
+
+// The address of a light identifies it, hits only know the light as &dyn Light
+fn light_key(light: &dyn Light) -> usize {
+    light as *const dyn Light as *const () as usize
+}
+
+#[derive(Default)]
+pub struct LightBvh {
+    lights: Vec<Arc<dyn Light>>,
+    nodes: Vec<LightNode>,
+    // Path from the root to each light's leaf, bit i set for taking the
+    // second child at depth i
+    bit_trails: HashMap<usize, u64>,
+}
+
+const BUCKETS: usize = 12;
+// The bit trails have room for 64 levels, below this depth the splits are
+// balanced so that many lights still fit
+const MAX_SAH_DEPTH: u32 = 32;
+
+impl LightBvh {
+    // Lights without bounds or power are left out
+    pub fn new(lights: Vec<Arc<dyn Light>>) -> Self {
+        let mut bvh = LightBvh::default();
+        let mut bounded = Vec::new();
+        for light in lights {
+            if let Some(bounds) = light.bounds().filter(|b| b.phi > 0.0) {
+                bounded.push((bvh.lights.len(), bounds));
+                bvh.lights.push(light);
+            }
+        }
+        if !bounded.is_empty() {
+            bvh.build(&mut bounded, 0, 0);
+        }
+        bvh
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // Total power of the lights in the tree
+    pub fn phi(&self) -> f32 {
+        self.nodes.first().map_or(0.0, |root| root.bounds.phi)
+    }
+
+    fn build(&mut self, lights: &mut [(usize, LightBounds)], bit_trail: u64, depth: u32) -> LightBounds {
+        if lights.len() == 1 {
+            let (index, bounds) = lights[0];
+            self.nodes.push(LightNode { bounds, index, is_leaf: true });
+            self.bit_trails.insert(light_key(&*self.lights[index]), bit_trail);
+            return bounds;
+        }
+
+        let mut bbox = lights[0].1.bbox;
+        let mut centroid_min = lights[0].1.centroid();
+        let mut centroid_max = centroid_min;
+        for (_, b) in lights.iter() {
+            bbox = surrounding_box(bbox, b.bbox);
+            centroid_min = centroid_min.inf(&b.centroid());
+            centroid_max = centroid_max.sup(&b.centroid());
+        }
+
+        // Split along the bucket boundary with the lowest cost, the cost
+        // weighs power by the spread of directions and the size of the bounds
+        let bucket_of = |b: &LightBounds, dim: usize| {
+            let t = (b.centroid()[dim] - centroid_min[dim]) / (centroid_max[dim] - centroid_min[dim]);
+            ((t * BUCKETS as f32) as usize).min(BUCKETS - 1)
+        };
+        let mut best: Option<(f32, usize, usize)> = None;
+        for dim in 0..3 {
+            if centroid_max[dim] <= centroid_min[dim] || depth >= MAX_SAH_DEPTH {
+                continue;
+            }
+            let mut buckets: [Option<LightBounds>; BUCKETS] = [None; BUCKETS];
+            for (_, b) in lights.iter() {
+                let bucket = &mut buckets[bucket_of(b, dim)];
+                *bucket = Some(bucket.map_or(*b, |other| LightBounds::union(&other, b)));
+            }
+            for split in 0..BUCKETS - 1 {
+                let side = |range: &[Option<LightBounds>]| {
+                    range.iter().flatten().fold(None, |acc: Option<LightBounds>, b| Some(acc.map_or(*b, |acc| LightBounds::union(&acc, b))))
+                };
+                let cost = [side(&buckets[..=split]), side(&buckets[split + 1..])]
+                    .iter()
+                    .flatten()
+                    .map(|b| split_cost(b, &bbox, dim))
+                    .sum::<f32>();
+                if cost > 0.0 && best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    best = Some((cost, dim, split));
+                }
+            }
+        }
+
+        // Without a split that has a cost, e.g. for point lights which have no
+        // area, split in the middle of the widest axis
+        let mut mid = 0;
+        if let Some((_, dim, split)) = best {
+            lights.sort_by_key(|(_, b)| bucket_of(b, dim) > split);
+            mid = lights.iter().filter(|(_, b)| bucket_of(b, dim) <= split).count();
+        }
+        if mid == 0 || mid == lights.len() {
+            let dim = (centroid_max - centroid_min).imax();
+            lights.sort_by(|(_, a), (_, b)| a.centroid()[dim].total_cmp(&b.centroid()[dim]));
+            mid = lights.len() / 2;
+        }
+
+        let node = self.nodes.len();
+        self.nodes.push(LightNode { bounds: lights[0].1, index: 0, is_leaf: false });
+        let (first, second) = lights.split_at_mut(mid);
+        let first_bounds = self.build(first, bit_trail, depth + 1);
+        self.nodes[node].index = self.nodes.len();
+        let second_bounds = self.build(second, bit_trail | (1 << depth), depth + 1);
+        self.nodes[node].bounds = LightBounds::union(&first_bounds, &second_bounds);
+        self.nodes[node].bounds
+    }
+
+    // A light for a shading point and the probability it was picked with
+    pub fn sample(&self, p: Vector3<f32>, n: Vector3<f32>, u: f32) -> Option<(&Arc<dyn Light>, f32)> {
+        // The root has no parent to check its importance
+        if self.nodes.first()?.bounds.importance(p, n) <= 0.0 {
+            return None;
+        }
+        let mut node = 0;
+        let mut u = u;
+        let mut pmf = 1.0;
+        while !self.nodes[node].is_leaf {
+            let children = [node + 1, self.nodes[node].index];
+            let importance = children.map(|child| self.nodes[child].bounds.importance(p, n));
+            let total = importance[0] + importance[1];
+            if total <= 0.0 {
+                return None;
+            }
+            let p_first = importance[0] / total;
+            if u < p_first {
+                node = children[0];
+                u = (u / p_first).min(1.0 - f32::EPSILON);
+                pmf *= p_first;
+            } else {
+                node = children[1];
+                u = ((u - p_first) / (1.0 - p_first)).min(1.0 - f32::EPSILON);
+                pmf *= 1.0 - p_first;
+            }
+        }
+        Some((&self.lights[self.nodes[node].index], pmf))
+    }
+
+    // Probability that sample picks the light at this shading point
+    pub fn pmf(&self, p: Vector3<f32>, n: Vector3<f32>, light: &dyn Light) -> f32 {
+        let mut bit_trail = match self.bit_trails.get(&light_key(light)) {
+            Some(&bit_trail) => bit_trail,
+            None => return 0.0,
+        };
+        match self.nodes.first() {
+            Some(root) if root.bounds.importance(p, n) > 0.0 => {}
+            _ => return 0.0,
+        }
+        let mut node = 0;
+        let mut pmf = 1.0;
+        while !self.nodes[node].is_leaf {
+            let children = [node + 1, self.nodes[node].index];
+            let importance = children.map(|child| self.nodes[child].bounds.importance(p, n));
+            let total = importance[0] + importance[1];
+            if total <= 0.0 {
+                return 0.0;
+            }
+            let child = (bit_trail & 1) as usize;
+            pmf *= importance[child] / total;
+            node = children[child];
+            bit_trail >>= 1;
+        }
+        pmf
+    }
+}
+
+// Surface area heuristic with the solid angle the emission spreads over,
+// boxes that are long along the split axis are preferred
+fn split_cost(bounds: &LightBounds, parent: &AABB, dim: usize) -> f32 {
+    let theta_o = safe_acos(bounds.normals.cos_theta);
+    let theta_e = safe_acos(bounds.cos_theta_e);
+    let theta_w = (theta_o + theta_e).min(f32::consts::PI);
+    let sin_theta_o = safe_sqrt(1.0 - bounds.normals.cos_theta * bounds.normals.cos_theta);
+    let m_omega = 2.0 * f32::consts::PI * (1.0 - bounds.normals.cos_theta)
+        + f32::consts::PI / 2.0
+            * (2.0 * theta_w * sin_theta_o - (theta_o - 2.0 * theta_w).cos() - 2.0 * theta_o * sin_theta_o + bounds.normals.cos_theta);
+    let diagonal = parent.max - parent.min;
+    let kr = diagonal.max() / diagonal[dim];
+    let d = bounds.bbox.max - bounds.bbox.min;
+    let area = 2.0 * (d.x * d.y + d.y * d.z + d.z * d.x);
+    bounds.phi * m_omega * kr * area
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::{PointLight, SpotLight};
+    use crate::vec::{vec, vec_one};
+
+    fn lights() -> Vec<Arc<dyn Light>> {
+        let mut lights: Vec<Arc<dyn Light>> = Vec::new();
+        for i in 0..7 {
+            let x = i as f32 * 1.5 - 4.0;
+            lights.push(Arc::new(PointLight::new(vec(x, 2.0, (i % 3) as f32), vec_one(), 100.0 * (i + 1) as f32)));
+            lights.push(Arc::new(SpotLight::new(vec(x, 4.0, -1.0), vec(x, 0.0, 0.0), vec_one(), 500.0, 20.0, 30.0)));
+        }
+        lights
+    }
+
+    #[test]
+    fn pmf_matches_sample() {
+        let bvh = LightBvh::new(lights());
+        let shading = [
+            (vec(0.0, 0.0, 0.0), vec(0.0, 1.0, 0.0)),
+            (vec(-3.0, 1.0, 2.0), vec(1.0, 0.0, 0.0)),
+            // Volume scattering, no normal
+            (vec(2.0, 3.0, -2.0), vec(0.0, 0.0, 0.0)),
+        ];
+        for (p, n) in shading {
+            let mut total = 0.0;
+            for light in &bvh.lights {
+                total += bvh.pmf(p, n, &**light);
+            }
+            // Less than 1 where both children of a node turn out unimportant
+            assert!(total > 0.0 && total <= 1.0 + 1e-4, "pmfs sum to {}", total);
+
+            for i in 0..256 {
+                let u = (i as f32 + 0.5) / 256.0;
+                let Some((light, pmf)) = bvh.sample(p, n, u) else { continue };
+                let expected = bvh.pmf(p, n, &**light);
+                assert!((pmf - expected).abs() <= 1e-5 * expected.max(1.0), "sampled {} but pmf is {}", pmf, expected);
+            }
+        }
+    }
+}
@@ -17,17 +17,42 @@ mod volume;
 mod triangle;
 mod mesh;
 mod utils;
-
+mod adaptive;
+mod tiles;
+mod checkpoint;
+mod overlay;
+mod preview;
+mod stats;
+mod microfacet;
+mod principled;
+mod layered;
+mod spectrum;
+mod medium_stack;
+mod subsurface;
+mod phase;
+mod voxel;
+mod sky;
+mod light;
+mod light_bvh;
+mod ies;
+
+use adaptive::{PixelStats, heatmap_buffer, save_heatmap};
+use camera::Camera;
+use checkpoint::Checkpoint;
 use cmd_lib::run_cmd;
-use hittable::{Hittable};
-use ray::Ray;
-use vec::{vec_zero, has_nan};
+use hittable::{HitRecord, Hittable};
+use ray::{Ray, RayKind};
+use vec::{vec_zero, vec_one, has_nan};
 use image::{ImageBuffer, hdr::{HDREncoder}, Rgb};
 use material::EnvironmentMaterial;
 use minifb::{Key, ScaleMode, Window, WindowOptions};
-use nalgebra::Vector3;
-use rand::{thread_rng, Rng};
+use nalgebra::{Vector2, Vector3};
+use overlay::draw_text;
+use preview::{DebugView, Preview, pack_color};
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
 use rayon::prelude::*;
+use stats::{Counter, Stats};
+use spectrum::SampledWavelengths;
 use scenes::{
     Scene,
     // random_scene_bvh::random_scene_bvh,
@@ -41,15 +66,41 @@ use scenes::{
     // cornell_box_texture_filtering::scene
     env_scene::scene
 };
-use std::{f32, fs, sync::Arc, io, time::Instant};
+use std::{f32, fs, sync::Arc, io, time::{Duration, Instant}};
 use texture::hdr_image_loader;
-
-static mut RAY_COUNT: u32 = 0;
+use volume::MediumEvent;
+use light::{LightSampler, power_heuristic, ALL_LIGHT_GROUPS};
+use tiles::{CropOutput, CropWindow, Rect, TileOrder, TileScheduler, crop_buffer, generate_tiles, tile_seed};
 
 const WIDTH: usize = 1000;
 const HEIGHT: usize = 500;
 const HDR_OUTPUT: bool = true;
 const DENOISE: bool = true;
+const ADAPTIVE_SAMPLING: bool = true;
+const ADAPTIVE_MIN_SAMPLES: u32 = 16;
+// Pixels stop sampling once their relative error drops below this
+const NOISE_THRESHOLD: f32 = 0.01;
+// Noisy pixels take up to this many samples per pass, in proportion to their error
+const ADAPTIVE_MAX_PASS_SAMPLES: u32 = 8;
+// Converged pixels still get a sample every this many passes, in case they
+// only looked converged, e.g. with no variance because rare paths weren't found yet
+const ADAPTIVE_RECHECK_INTERVAL: u32 = 16;
+const TILE_SIZE: u32 = 32;
+const TILE_ORDER: TileOrder = TileOrder::Spiral;
+const TILE_PROGRESS: bool = false;
+// Only pixels inside the crop window are traced, e.g. Some(CropWindow::Normalized(0.25, 0.25, 0.75, 0.75))
+// or Some(CropWindow::Pixels(Rect { x0: 200, y0: 100, x1: 600, y1: 400 }))
+const CROP_WINDOW: Option<CropWindow> = None;
+const CROP_OUTPUT: CropOutput = CropOutput::FullFrame;
+const CHECKPOINT_PATH: &str = "output/checkpoint/render.ckpt";
+// Seconds between automatic checkpoints, None disables checkpointing
+const CHECKPOINT_INTERVAL: Option<u64> = Some(300);
+// Continue accumulating from CHECKPOINT_PATH instead of starting from scratch
+const RESUME: bool = false;
+// Trace sampled wavelengths instead of RGB, needed for dispersion
+const SPECTRAL: bool = false;
+// Scattering events and boundary crossings of media that don't count towards the path depth
+const MAX_VOLUME_BOUNCES: u32 = 256;
 
 fn clamp(x: f32, min: f32, max: f32) -> f32 {
     if x < min { return min; }
@@ -57,33 +108,146 @@ fn clamp(x: f32, min: f32, max: f32) -> f32 {
     x
 }
 
-fn ray_color(ray: &Ray, world: &Arc<dyn Hittable>, environment: &Arc<dyn EnvironmentMaterial>, depth: u32) -> Vector3<f32> {
-    unsafe {
-        RAY_COUNT += 1;
-    }
-    if depth <= 0 {
-        return Vector3::new(0.0, 0.0, 0.0);
-    }
-
-    if let Some(hit_rec) = world.hit(ray, 0.001, f32::MAX) {
-        if let Some((new_ray, attenuation)) = hit_rec.material.scatter(&ray, &hit_rec) {
-            if has_nan(&attenuation) {
-                return vec_zero();
+fn ray_color(ray: &Ray, world: &Arc<dyn Hittable>, environment: &Arc<dyn EnvironmentMaterial>, lights: &LightSampler, max_depth: u32) -> Vector3<f32> {
+    let mut rng = thread_rng();
+    let mut ray = ray.clone();
+    let mut throughput = vec_one();
+    let mut radiance = vec_zero();
+    // Materials and lights are RGB, in spectral mode they are evaluated at the ray's wavelengths
+    let to_spectrum = |rgb: Vector3<f32>, ray: &Ray| match &ray.wavelengths {
+        Some(wavelengths) => spectrum::upsample(rgb, wavelengths),
+        None => rgb,
+    };
+
+    let mut depth = 0;
+    let mut volume_bounces = 0;
+    // Where the current ray was scattered, the normal there and the density
+    // it was sampled with, when lights were also sampled there and emission
+    // needs MIS
+    let mut mis_origin: Option<(Vector3<f32>, Vector3<f32>, f32)> = None;
+    // Light groups of the object the current ray left, for light linking
+    let mut origin_groups = ALL_LIGHT_GROUPS;
+    while depth < max_depth {
+        stats::inc(if depth == 0 && volume_bounces == 0 { Counter::CameraRays } else { Counter::Bounces });
+
+        let mut hit = world.hit(&ray, 0.001, f32::MAX);
+        let mut volume_scatter = false;
+
+        // Inside an object with an interior medium the ray can scatter or be
+        // absorbed before it reaches the next surface, and pick up the light
+        // the medium emits on the way
+        let media = ray.media.as_ref().map(|media| media.media()).unwrap_or_default();
+        if !media.is_empty() {
+            let t_max = hit.as_ref().map_or(f32::MAX, |h| h.t);
+            let sample = volume::sample(&media, &ray, t_max);
+            radiance += throughput.component_mul(&sample.emitted);
+            match sample.event {
+                MediumEvent::Absorbed => {
+                    stats::record_path_length(depth + 1);
+                    return radiance;
+                }
+                MediumEvent::Pass { weight } => {
+                    throughput = throughput.component_mul(&weight);
+                }
+                MediumEvent::Scatter { t, weight, phase_function } => {
+                    throughput = throughput.component_mul(&weight);
+                    let p = ray.at(t);
+                    hit = Some(HitRecord::new(t, p, -ray.direction(), &ray, phase_function, Vector2::zeros()));
+                    volume_scatter = true;
+                }
             }
-            return attenuation.component_mul(&ray_color(&new_ray, world, environment, depth - 1));
-        }
-        let emitted = hit_rec.material.emitted(ray, &hit_rec);
-        if has_nan(&emitted) {
-            return vec_zero();
         }
-        return emitted;
-    } else {
-        let emitted = environment.emit(ray);
-        if has_nan(&emitted) {
-            return vec_zero();
+
+        if let Some(hit_rec) = hit {
+            // Volume scattering and crossing volume boundaries
+            volume_scatter |= !hit_rec.material.is_solid();
+            // Media have no normal to weigh the lights with
+            let normal = if volume_scatter { Vector3::zeros() } else { hit_rec.normal };
+            let emitted = hit_rec.material.emitted(&ray, &hit_rec);
+            let linked = hit_rec.material.area_light().is_none_or(|light| light.light_groups() & origin_groups != 0);
+            if linked && !has_nan(&emitted) {
+                let weight = match (mis_origin, hit_rec.material.area_light()) {
+                    (Some((origin, origin_normal, scatter_pdf)), Some(light)) => {
+                        power_heuristic(scatter_pdf, lights.pmf(origin, origin_normal, light) * light.pdf(origin, &hit_rec))
+                    }
+                    _ => 1.0,
+                };
+                radiance += throughput.component_mul(&to_spectrum(emitted, &ray)) * weight;
+            }
+
+            // Light sampling, for materials that can be evaluated
+            if let Some((light, pmf)) = lights.sample(hit_rec.p, normal, rng.gen()).filter(|(light, _)| light.light_groups() & hit_rec.light_groups != 0) {
+                if let Some(sample) = light.sample(hit_rec.p, Vector2::new(rng.gen(), rng.gen())) {
+                    if let Some((f, scatter_pdf)) = hit_rec.material.eval(&ray, &hit_rec, sample.direction) {
+                        if f.max() > 0.0 {
+                            let mut shadow_ray = Ray::new(hit_rec.p, sample.direction);
+                            shadow_ray.wavelengths = ray.wavelengths;
+                            shadow_ray.media = ray.media.clone();
+                            let transmittance = volume::shadow_transmittance(world, &shadow_ray, sample.distance * (1.0 - 1e-4));
+                            let light_pdf = pmf * sample.pdf;
+                            let weight = if sample.delta { 1.0 } else { power_heuristic(light_pdf, scatter_pdf) };
+                            let contribution = to_spectrum(f, &ray)
+                                .component_mul(&to_spectrum(sample.radiance, &ray))
+                                .component_mul(&transmittance) * (weight / light_pdf);
+                            if !has_nan(&contribution) {
+                                radiance += throughput.component_mul(&contribution);
+                            }
+                        }
+                    }
+                }
+            }
+
+            if let Some((mut new_ray, attenuation)) = hit_rec.material.scatter(&ray, &hit_rec) {
+                if has_nan(&attenuation) {
+                    stats::record_path_length(depth + 1);
+                    return radiance;
+                }
+                throughput = throughput.component_mul(&to_spectrum(attenuation, &ray));
+                match (ray.wavelengths, new_ray.wavelengths) {
+                    // Dropping the secondary wavelengths moves their weight to the hero
+                    (Some(old), Some(new)) if new.hero_only && !old.hero_only => {
+                        throughput = throughput.component_mul(&Vector3::new(3.0, 0.0, 0.0));
+                    }
+                    (old, None) => new_ray.wavelengths = old,
+                    _ => {}
+                }
+                if new_ray.media.is_none() {
+                    new_ray.media = ray.media.clone();
+                }
+                // Rays passing straight through medium boundaries keep their origin
+                if new_ray.direction() != ray.direction() {
+                    // Specular directions can't be sampled from lights, their emission needs no MIS
+                    mis_origin = if lights.is_empty() || new_ray.kind == RayKind::Specular {
+                        None
+                    } else {
+                        hit_rec.material.eval(&ray, &hit_rec, new_ray.direction()).map(|(_, pdf)| (hit_rec.p, normal, pdf))
+                    };
+                    origin_groups = hit_rec.light_groups;
+                } else {
+                    new_ray.kind = ray.kind;
+                }
+                ray = new_ray;
+                // Random walks inside dense media take many steps, those have their own budget
+                if volume_scatter && volume_bounces < MAX_VOLUME_BOUNCES {
+                    volume_bounces += 1;
+                } else {
+                    depth += 1;
+                }
+                continue;
+            }
+            stats::record_path_length(depth + 1);
+            return radiance;
+        } else {
+            stats::record_path_length(depth + 1);
+            let emitted = to_spectrum(environment.emit(&ray), &ray);
+            if !has_nan(&emitted) {
+                radiance += throughput.component_mul(&emitted);
+            }
+            return radiance;
         }
-        emitted
     }
+    stats::record_path_length(max_depth);
+    radiance
 }
 
 fn ray_albedo(ray: &Ray, world: &Arc<dyn Hittable>) -> Vector3<f32> {
@@ -102,6 +266,56 @@ fn ray_normal(ray: &Ray, world: &Arc<dyn Hittable>) -> Vector3<f32> {
     vec_zero()
 }
 
+// Renders one noise-free value per pixel center inside `rect`, used for the
+// albedo and normal buffers the denoiser needs and for the preview debug views.
+fn render_aov(
+    cam: &Camera,
+    world: &Arc<dyn Hittable>,
+    nx: u32,
+    ny: u32,
+    rect: &Rect,
+    aov: fn(&Ray, &Arc<dyn Hittable>) -> Vector3<f32>,
+) -> Vec<f32> {
+    (0..ny)
+        .into_par_iter()
+        .flat_map(|y| {
+            (0..nx)
+                .flat_map(|x| {
+                    if !rect.contains(x, y) {
+                        return vec![0.0, 0.0, 0.0];
+                    }
+                    let u = (x as f32) / nx as f32;
+                    let v = (ny as f32 - (y as f32)) / ny as f32;
+                    let ray = cam.get_ray_an(u, v);
+                    let col = aov(&ray, world);
+                    vec![col.x, col.y, col.z]
+                }).collect::<Vec<f32>>()
+        }).collect::<Vec<f32>>()
+}
+
+fn max_pixel_samples(pixel_stats: &[PixelStats]) -> u32 {
+    pixel_stats.iter().map(|s| s.samples).max().unwrap_or(0)
+}
+
+fn save_checkpoint(nx: u32, ny: u32, completed_passes: u32, seed: u64, cam: &Camera, image_buf: &[f32], pixel_stats: &[PixelStats]) {
+    let checkpoint = Checkpoint {
+        width: nx,
+        height: ny,
+        completed_passes,
+        seed,
+        view: cam.view(),
+        image_buf: image_buf.to_vec(),
+        pixel_stats: pixel_stats.to_vec(),
+    };
+    match checkpoint.save(CHECKPOINT_PATH) {
+        Ok(()) => println!(
+            "Saved checkpoint to {} after {} passes, at most {} samples per pixel",
+            CHECKPOINT_PATH, completed_passes, max_pixel_samples(pixel_stats)
+        ),
+        Err(e) => println!("Failed to save checkpoint: {}", e),
+    }
+}
+
 fn display() -> Window {
     let mut window = Window::new(
         "Test",
@@ -130,67 +344,214 @@ fn main() {
 
     let nx: u32 = WIDTH as u32;
     let ny: u32 = HEIGHT as u32;
-    let ns = 10000;
+    // Sample budget per pixel
+    let ns: u32 = 10000;
     let max_depth = 50;
 
     let mut window = display();  
     let mut u32_buffer: Vec<u32>;
-    let mut completed_samples = 0;
+    let mut completed_passes = 0;
     let mut save_images = false;
 
 
     let mut image_buf: Vec<f32> = vec![0.0; (nx * ny * 3) as usize];
+    let mut pixel_stats: Vec<PixelStats> = vec![PixelStats::default(); (nx * ny) as usize];
+    let mut seed = thread_rng().gen::<u64>();
 
     // let aspect = nx as f32 / ny as f32;
     let scene = scene();
 
     let world = scene.objects;
     let environment = scene.environment;
-    let cam = scene.camera;
+    let scene_radius = world.bounding_box().map_or(0.0, |b| (b.max - b.min).magnitude() / 2.0);
+    let lights = LightSampler::new(scene.lights, scene_radius);
+    let mut cam = scene.camera;
+
+    if RESUME {
+        let checkpoint = Checkpoint::load(CHECKPOINT_PATH).expect("Can't load checkpoint");
+        assert!(checkpoint.width == nx && checkpoint.height == ny, "Checkpoint resolution doesn't match");
+        image_buf = checkpoint.image_buf;
+        pixel_stats = checkpoint.pixel_stats;
+        completed_passes = checkpoint.completed_passes;
+        seed = checkpoint.seed;
+        cam.set_view(checkpoint.view);
+        // The noise threshold may have changed since the checkpoint was written
+        for stats in pixel_stats.iter_mut() {
+            if ADAPTIVE_SAMPLING {
+                stats.update_convergence(ADAPTIVE_MIN_SAMPLES, NOISE_THRESHOLD);
+            } else {
+                stats.converged = false;
+            }
+        }
+        println!("Resumed from {} after {} passes, at most {} samples per pixel", CHECKPOINT_PATH, completed_passes, max_pixel_samples(&pixel_stats));
+    }
 
     
-    let now = Instant::now();
-
-    // This incremental method is actually twice as fast as the more functional approach.
-    for n in 0..ns {
-        image_buf = (0..ny)
-            .into_par_iter()
-            .flat_map(|y| {
-                let mut rng = thread_rng();
-                (0..nx)
-                    .flat_map(|x| {
-                        let u = (x as f32 + rng.gen::<f32>()) / nx as f32;
-                        let v = (ny as f32 - (y as f32 + rng.gen::<f32>())) / ny as f32;
-                        let ray = cam.get_ray(u, v);
-                        let col = ray_color(&ray, &world, &environment, max_depth);
-                        let offset = ((y * nx + x) * 3) as usize;
-                        vec![
-                            col.x + image_buf[offset],
-                            col.y + image_buf[offset + 1],
-                            col.z + image_buf[offset + 2],
-                        ]
-                    })
-                    .collect::<Vec<f32>>()
-            })
-            .collect::<Vec<f32>>();
-
-        let pixel_scale = 1.0 / ((n+1) as f32);
-        u32_buffer = image_buf
-            .iter()
-            .map(|sp| clamp((*sp * pixel_scale).sqrt() * 255.99, 0.0, 255.0) as u8)
-            .collect::<Vec<u8>>()
-            .chunks(3)
-            .map(|v| ((v[0] as u32) << 16) | ((v[1] as u32) << 8) | v[2] as u32)
-            .collect();
+    let mut now = Instant::now();
+
+    let crop = CROP_WINDOW.map(|c| c.to_rect(nx, ny));
+    let render_rect = crop.unwrap_or_else(|| Rect::new(0, 0, nx, ny));
+    let tiles = generate_tiles(nx, ny, TILE_SIZE, TILE_ORDER, crop);
+    let mut scheduler = TileScheduler::new(&tiles);
+    scheduler.report_progress = TILE_PROGRESS;
+    let mut last_checkpoint = Instant::now();
+    let start_stats = stats::snapshot();
+    // Time spent tracing, excluding the final albedo and normal passes
+    let mut render_time = Duration::default();
+    let mut passes = 0;
+    // Set while every pixel gets a final sample after all of them converged
+    let mut final_recheck = false;
+
+    let mut preview = Preview::new();
+    // Albedo and normal previews, rendered on demand and dropped when the camera moves
+    let mut preview_albedo: Option<Vec<f32>> = None;
+    let mut preview_normal: Option<Vec<f32>> = None;
+
+    loop {
+        let n = completed_passes;
+        let pass_start = Instant::now();
+        let pass_start_stats = stats::snapshot();
+
+        // The camera may start inside fog or under water, and it moves between passes
+        let camera_media = volume::media_at(&world, cam.origin);
+        let rendered_tiles = scheduler.render(|tile| {
+            let mut rng = StdRng::seed_from_u64(tile_seed(seed, n, tile));
+            tile.pixels()
+                .map(|(x, y)| {
+                    let stats = &pixel_stats[(y * nx + x) as usize];
+                    let samples = if !ADAPTIVE_SAMPLING {
+                        1
+                    } else if stats.converged {
+                        (n % ADAPTIVE_RECHECK_INTERVAL == 0) as u32
+                    } else {
+                        stats.pass_samples(ADAPTIVE_MIN_SAMPLES, NOISE_THRESHOLD, ADAPTIVE_MAX_PASS_SAMPLES)
+                    }.min(ns.saturating_sub(stats.samples));
+                    (0..samples)
+                        .map(|_| {
+                            let u = (x as f32 + rng.gen::<f32>()) / nx as f32;
+                            let v = (ny as f32 - (y as f32 + rng.gen::<f32>())) / ny as f32;
+                            let mut ray = cam.get_ray(u, v);
+                            if !camera_media.is_empty() {
+                                ray.media = Some(camera_media.clone());
+                            }
+                            if SPECTRAL {
+                                let wavelengths = SampledWavelengths::sample(rng.gen::<f32>());
+                                ray.wavelengths = Some(wavelengths);
+                                return spectrum::to_rgb(ray_color(&ray, &world, &environment, &lights, max_depth), &wavelengths);
+                            }
+                            ray_color(&ray, &world, &environment, &lights, max_depth)
+                        })
+                        .collect::<Vec<Vector3<f32>>>()
+                })
+                .collect::<Vec<Vec<Vector3<f32>>>>()
+        });
+
+        for (tile, colors) in rendered_tiles {
+            for ((x, y), samples) in tile.pixels().zip(colors) {
+                for col in samples {
+                    let i = (y * nx + x) as usize;
+                    image_buf[i * 3] += col.x;
+                    image_buf[i * 3 + 1] += col.y;
+                    image_buf[i * 3 + 2] += col.z;
+                    pixel_stats[i].add_sample(col);
+                    if ADAPTIVE_SAMPLING {
+                        pixel_stats[i].update_convergence(ADAPTIVE_MIN_SAMPLES, NOISE_THRESHOLD);
+                    }
+                }
+            }
+        }
+        completed_passes += 1;
+        let max_samples = max_pixel_samples(&pixel_stats);
+
+        let pass_time = pass_start.elapsed();
+        render_time += pass_time;
+        passes += 1;
+        let pass_stats = stats::snapshot().since(&pass_start_stats);
+
+        preview.handle_toggles(&window);
+        u32_buffer = match preview.view {
+            DebugView::Beauty => image_buf
+                .chunks(3)
+                .zip(pixel_stats.iter())
+                .map(|(sp, stats)| {
+                    let pixel_scale = 1.0 / stats.samples.max(1) as f32;
+                    preview.tonemap(Vector3::new(sp[0], sp[1], sp[2]) * pixel_scale)
+                })
+                .collect(),
+            DebugView::Albedo => preview_albedo
+                .get_or_insert_with(|| render_aov(&cam, &world, nx, ny, &render_rect, ray_albedo))
+                .chunks(3)
+                .map(|c| pack_color(Vector3::new(c[0], c[1], c[2])))
+                .collect(),
+            DebugView::Normal => preview_normal
+                .get_or_insert_with(|| render_aov(&cam, &world, nx, ny, &render_rect, ray_normal))
+                .chunks(3)
+                .map(|c| pack_color((Vector3::new(c[0], c[1], c[2]) + vec_one()) / 2.0))
+                .collect(),
+            DebugView::Heatmap => heatmap_buffer(&pixel_stats)
+                .into_iter()
+                .map(pack_color)
+                .collect(),
+        };
+
+        if preview.show_overlay {
+            let overlay = format!(
+                "{} SPP  {:.2} MRAYS/S  {:.1}S  EXP {:+.1}  {}",
+                max_samples,
+                pass_stats.mrays_per_sec(pass_time),
+                now.elapsed().as_secs_f32(),
+                preview.exposure,
+                preview.view.name()
+            );
+            draw_text(&mut u32_buffer, WIDTH, HEIGHT, 4, 4, &overlay, 2);
+        }
 
         window
             .update_with_buffer(&u32_buffer, WIDTH, HEIGHT)
             .unwrap();
 
-        unsafe {
-            println!("samples: {}, rays: {:.2} M", n, RAY_COUNT as f32 / 1e6);
+        // Pixels that still need samples, the others converged or used up the budget
+        let active_pixels = tiles.iter()
+            .flat_map(|tile| tile.pixels())
+            .map(|(x, y)| &pixel_stats[(y * nx + x) as usize])
+            .filter(|stats| !stats.converged && stats.samples < ns)
+            .count();
+        println!(
+            "pass: {}, max samples per pixel: {}, pass time: {:.2?}, {:.2} Mrays/s, active pixels: {}",
+            n, max_samples, pass_time, pass_stats.mrays_per_sec(pass_time), active_pixels
+        );
+
+        if preview.handle_camera_input(&window, &mut cam) {
+            // Restart accumulation from the new view
+            image_buf.iter_mut().for_each(|v| *v = 0.0);
+            pixel_stats.iter_mut().for_each(|s| *s = PixelStats::default());
+            completed_passes = 0;
+            final_recheck = false;
+            preview_albedo = None;
+            preview_normal = None;
+            now = Instant::now();
+            continue;
+        }
+
+        let checkpoint_due = CHECKPOINT_INTERVAL
+            .is_some_and(|secs| last_checkpoint.elapsed() >= Duration::from_secs(secs));
+        if checkpoint_due || window.is_key_released(Key::C) {
+            save_checkpoint(nx, ny, completed_passes, seed, &cam, &image_buf, &pixel_stats);
+            last_checkpoint = Instant::now();
+        }
+
+        if active_pixels > 0 {
+            final_recheck = false;
+        } else if !final_recheck && pixel_stats.iter().any(|s| s.converged && s.samples < ns) {
+            // Pixels that converged early may only have looked converged,
+            // give every one of them another sample before stopping
+            pixel_stats.iter_mut().for_each(|s| s.converged = false);
+            final_recheck = true;
+        } else {
+            println!("All pixels done after {} passes, at most {} samples per pixel", completed_passes, max_samples);
+            save_images = true;
+            break;
         }
-        completed_samples += 1;
 
         if !window.is_open() || window.is_key_down(Key::Escape) || window.is_key_released(Key::Escape) {
             break;
@@ -201,52 +562,55 @@ fn main() {
             break;
         }
     }
-    
-    let albedo_buf = (0..ny)
-        .into_par_iter()
-        .flat_map(|y| {
-            (0..nx)
-                .flat_map(|x| {
-                    let u = (x as f32) / nx as f32;
-                    let v = (ny as f32 - (y as f32)) / ny as f32;
-                    let ray = cam.get_ray_an(u, v);
-                    let col = ray_albedo(&ray, &world);
-                    vec![col.x, col.y, col.z]
-                }).collect::<Vec<f32>>()
-        }).collect::<Vec<f32>>();
 
-    let normal_buf = (0..ny)
-        .into_par_iter()
-        .flat_map(|y| {
-            (0..nx)
-                .flat_map(|x| {
-                    let u = (x as f32) / nx as f32;
-                    let v = (ny as f32 - (y as f32)) / ny as f32;
-                    let ray = cam.get_ray_an(u, v);
-                    let col = ray_normal(&ray, &world);
-                    vec![col.x, col.y, col.z]
-                }).collect::<Vec<f32>>()
-        }).collect::<Vec<f32>>();
-
-    if completed_samples == ns {
-        save_images = true;
+    // Keep the progress so the render can be resumed, also when the window was closed
+    if CHECKPOINT_INTERVAL.is_some() {
+        save_checkpoint(nx, ny, completed_passes, seed, &cam, &image_buf, &pixel_stats);
     }
     
+    // Per-pixel averages, pixels may have different sample counts with adaptive sampling
+    let final_buf = image_buf
+        .chunks(3)
+        .zip(pixel_stats.iter())
+        .flat_map(|(sp, stats)| {
+            let pixel_scale = 1.0 / stats.samples.max(1) as f32;
+            vec![sp[0] * pixel_scale, sp[1] * pixel_scale, sp[2] * pixel_scale]
+        })
+        .collect::<Vec<f32>>();
+
+    let albedo_buf = render_aov(&cam, &world, nx, ny, &render_rect, ray_albedo);
+    let normal_buf = render_aov(&cam, &world, nx, ny, &render_rect, ray_normal);
+
     let elapsed = now.elapsed();
-    unsafe {
-        println!("Elapsed time: {:.2?}, total samples per pixel: {}, total rays: {:.2} M", elapsed, completed_samples, RAY_COUNT as f32 / 1e6);
-    }
+    let total_samples: u64 = pixel_stats.iter().map(|s| s.samples as u64).sum();
+    let traced_pixels: u32 = tiles.iter().map(|tile| tile.area()).sum();
+    println!("Elapsed time: {:.2?}, max samples per pixel: {}, average samples per pixel: {:.1}", 
+        elapsed, max_pixel_samples(&pixel_stats), total_samples as f32 / traced_pixels.max(1) as f32);
+    let render_stats: Stats = stats::snapshot().since(&start_stats);
+    render_stats.print_report(render_time, passes);
 
 
     if save_images {
 
+        // With a cropped output only the crop window is written, everything else
+        // is the full frame with black outside of the crop window.
+        let output_rect = match CROP_OUTPUT {
+            CropOutput::Cropped => render_rect,
+            CropOutput::FullFrame => Rect::new(0, 0, nx, ny),
+        };
+        let (out_nx, out_ny) = (output_rect.width(), output_rect.height());
+        let final_buf = crop_buffer(&final_buf, nx, 3, &output_rect);
+        let albedo_buf = crop_buffer(&albedo_buf, nx, 3, &output_rect);
+        let normal_buf = crop_buffer(&normal_buf, nx, 3, &output_rect);
+        let pixel_stats = crop_buffer(&pixel_stats, nx, 1, &output_rect);
+        let (nx, ny) = (out_nx, out_ny);
+
         let mut imgbuf = ImageBuffer::new(nx, ny);
-        let pixel_scale = 1.0 / completed_samples as f32;
         for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
             let offset = ((y * nx + x) * 3) as usize;
-            let r = clamp((image_buf[offset] * pixel_scale as f32).sqrt() * 255.99, 0.0, 255.0) as u8;
-            let g = clamp((image_buf[offset + 1] * pixel_scale as f32).sqrt() * 255.99, 0.0, 255.0) as u8;
-            let b = clamp((image_buf[offset + 2] * pixel_scale as f32).sqrt() * 255.99, 0.0, 255.0) as u8;
+            let r = clamp(final_buf[offset].sqrt() * 255.99, 0.0, 255.0) as u8;
+            let g = clamp(final_buf[offset + 1].sqrt() * 255.99, 0.0, 255.0) as u8;
+            let b = clamp(final_buf[offset + 2].sqrt() * 255.99, 0.0, 255.0) as u8;
 
             *pixel = image::Rgb([r, g, b]);
         }
@@ -270,21 +634,33 @@ fn main() {
             let output_path = "output/png/".to_string() + &output_image_name + ".png";
             println!("Saved image to {}", output_path);
             imgbuf.save(output_path).unwrap();
+
+            if ADAPTIVE_SAMPLING {
+                let _ = fs::create_dir_all("output/heatmap/");
+                save_heatmap(&pixel_stats, nx, ny, &format!("output/heatmap/{}.png", output_image_name));
+            }
+
+            if CROP_OUTPUT == CropOutput::Cropped && crop.is_some() {
+                // Record where the cropped image sits in the full frame
+                let _ = fs::create_dir_all("output/datawindow/");
+                let data_window = format!(
+                    "display_window: 0 0 {} {}\ndata_window: {} {} {} {}\n",
+                    WIDTH, HEIGHT, output_rect.x0, output_rect.y0, output_rect.x1, output_rect.y1
+                );
+                fs::write(format!("output/datawindow/{}.txt", output_image_name), data_window).unwrap();
+            }
         }
 
 
         if HDR_OUTPUT {
-            let image_buf_rgb = image_buf.chunks(3).map(|pix| {
-                image::Rgb([
-                    pix[0] / completed_samples as f32, 
-                    pix[1] / completed_samples as f32, 
-                    pix[2] / completed_samples as f32])
+            let image_buf_rgb = final_buf.chunks(3).map(|pix| {
+                image::Rgb([pix[0], pix[1], pix[2]])
             }).collect::<Vec<Rgb<f32>>>();
 
             let file = fs::File::create(format!("output/hdr/{}.hdr", output_image_name)).unwrap();
             let encoder = HDREncoder::new(io::BufWriter::new(file));
 
-            encoder.encode(&image_buf_rgb[..], WIDTH, HEIGHT).unwrap();
+            encoder.encode(&image_buf_rgb[..], nx as usize, ny as usize).unwrap();
 
             let _ = fs::remove_file("output/temp/albedo.png");
             let _ = fs::remove_file("output/temp/normal.png");
@@ -3,8 +3,13 @@ use rand::{thread_rng, Rng};
 use std::{f32, sync::Arc};
 
 use crate::hittable::{HitRecord};
-use crate::ray::Ray;
-use crate::vec::{random_unit_vec, random_vec_in_unit_sphere};
+use crate::light::Light;
+use crate::medium_stack::{MediumEntry, MediumStack};
+use crate::microfacet::{Frame, Ggx, eval_dielectric, eval_reflection, fresnel_conductor, fresnel_dielectric, reflect_local, sample_dielectric};
+use crate::ray::{Ray, RayKind};
+use crate::spectrum::Dispersion;
+use crate::volume::Medium;
+use crate::vec::{random_cosine_direction, random_unit_vec, random_vec_in_unit_sphere};
 use crate::texture::{ConstantTex, Texture};
 use crate::vec::{vec, vec_zero, vec_one};
 
@@ -33,6 +38,27 @@ pub trait Material: Sync + Send {
     fn is_solid(&self) -> bool {
         true
     }
+    // Continuation of a shadow ray through surfaces that don't change its
+    // direction, like medium boundaries. None blocks the shadow ray.
+    fn shadow_pass(&self, _ray: &Ray, _hit: &HitRecord) -> Option<Ray> {
+        None
+    }
+    // Entry a ray pushes on its medium stack when it enters the object through
+    // this surface, None for surfaces that don't bound a medium
+    fn medium_entry(&self, _ray: &Ray, _media: &MediumStack) -> Option<MediumEntry> {
+        None
+    }
+    // BSDF times cosine towards `direction` and the density scatter samples
+    // it with, for light sampling. Materials that return None for every
+    // direction (specular or stochastic ones) only find lights by chance.
+    fn eval(&self, _ray: &Ray, _hit: &HitRecord, _direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        None
+    }
+    // The light this surface belongs to, for weighting emission that paths
+    // find by chance against light sampling
+    fn area_light(&self) -> Option<&dyn Light> {
+        None
+    }
 }
 
 pub struct Lambertian {
@@ -42,9 +68,15 @@ pub struct Lambertian {
 impl Material for Lambertian {
     fn scatter(&self, _: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
         let scatter_direction = hit.normal + random_unit_vec();
-        let scattered = Ray::new(hit.p, scatter_direction);
+        let scattered = Ray::new(hit.p, scatter_direction).with_kind(RayKind::Diffuse);
         Some((scattered, self.albedo.value(hit.uv, hit.p)))
     }
+
+    fn eval(&self, _ray: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        let cos_theta = hit.normal.normalize().dot(&direction.normalize()).max(0.0);
+        let pdf = cos_theta / f32::consts::PI;
+        Some((self.albedo.value(hit.uv, hit.p) * pdf, pdf))
+    }
 }
 
 pub struct Metal {
@@ -55,56 +87,207 @@ pub struct Metal {
 impl Material for Metal {
     fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
         let reflected = reflect(ray.direction().normalize(), hit.normal);
-        let scattered = Ray::new(hit.p, reflected + self.fuzz * random_vec_in_unit_sphere());
+        let kind = if self.fuzz > 0.0 { RayKind::Glossy } else { RayKind::Specular };
+        let scattered = Ray::new(hit.p, reflected + self.fuzz * random_vec_in_unit_sphere()).with_kind(kind);
         Some((scattered, self.albedo.value(hit.uv, hit.p)))
     }
 }
 
+// Rough metal with a GGX microfacet distribution. eta and k are the complex
+// index of refraction per color channel, roughness is read from the x channel
+// of the texture and anisotropy in [0, 1) stretches the highlight along the
+// tangent of Frame::from_normal, see Ggx::from_roughness.
+pub struct Conductor {
+    pub eta: Vector3<f32>,
+    pub k: Vector3<f32>,
+    pub roughness: Arc<dyn Texture>,
+    pub anisotropy: f32,
+}
+
+impl Conductor {
+    pub fn new(eta: Vector3<f32>, k: Vector3<f32>, roughness: f32) -> Self {
+        Conductor {
+            eta,
+            k,
+            roughness: ConstantTex::new_arc(vec(roughness, roughness, roughness)),
+            anisotropy: 0.0,
+        }
+    }
+
+    // Measured IORs sampled at roughly 650, 550 and 450 nm
+    pub fn gold(roughness: f32) -> Self {
+        Self::new(vec(0.143, 0.374, 1.442), vec(3.983, 2.385, 1.603), roughness)
+    }
+
+    pub fn silver(roughness: f32) -> Self {
+        Self::new(vec(0.155, 0.117, 0.138), vec(4.828, 3.122, 2.147), roughness)
+    }
+
+    pub fn copper(roughness: f32) -> Self {
+        Self::new(vec(0.200, 0.924, 1.102), vec(3.912, 2.452, 2.142), roughness)
+    }
+
+    pub fn aluminium(roughness: f32) -> Self {
+        Self::new(vec(1.657, 0.880, 0.521), vec(9.224, 6.270, 4.837), roughness)
+    }
+}
+
+impl Material for Conductor {
+    fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let distribution = Ggx::from_roughness(self.roughness.value(hit.uv, hit.p).x, self.anisotropy);
+        if distribution.is_smooth() {
+            let wi = vec(-wo.x, -wo.y, wo.z);
+            let attenuation = fresnel_conductor(wo.z, self.eta, self.k);
+            return Some((Ray::new(hit.p, frame.to_world(wi)).with_kind(RayKind::Specular), attenuation));
+        }
+
+        // Sampling visible normals leaves only F * G2 / G1 as the weight
+        let mut rng = thread_rng();
+        let m = distribution.sample_visible_normal(wo, rng.gen::<f32>(), rng.gen::<f32>());
+        let wi = reflect_local(wo, m);
+        if wi.z <= 0.0 {
+            return None;
+        }
+        let attenuation = fresnel_conductor(wo.dot(&m), self.eta, self.k)
+            * (distribution.g(wo, wi) / distribution.g1(wo));
+        Some((Ray::new(hit.p, frame.to_world(wi)).with_kind(RayKind::Glossy), attenuation))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        let distribution = Ggx::from_roughness(self.roughness.value(hit.uv, hit.p).x, self.anisotropy);
+        if distribution.is_smooth() {
+            return None;
+        }
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        let wi = frame.to_local(direction.normalize());
+        match eval_reflection(wo, wi, &distribution) {
+            Some((m, value, pdf)) => Some((fresnel_conductor(wo.dot(&m), self.eta, self.k) * value, pdf)),
+            None => Some((vec_zero(), 0.0)),
+        }
+    }
+}
+
+// Glass-like interface with a GGX microfacet BSDF, roughness is read from the
+// x channel of the texture. A roughness of zero gives a perfectly smooth surface.
+// With a dispersion model the IOR follows the hero wavelength in spectral mode
+// and ref_idx is only used for RGB rendering. Where dielectrics overlap the one
+// with the highest priority wins, see medium_stack.rs. Objects sharing one
+// Arc<Dielectric> are a single medium, e.g. both walls of hollow glass. The
+// optional medium fills the inside of the object, for colored glass, murky
+// water and the like.
 pub struct Dielectric {
     pub ref_idx: f32,
-    pub color: Vector3<f32>,
+    pub dispersion: Option<Dispersion>,
+    pub priority: u32,
     pub roughness: Arc<dyn Texture>,
-    pub density: f32,
+    pub medium: Option<Arc<dyn Medium>>,
+}
+
+impl Dielectric {
+    // The IOR at the hero wavelength in spectral mode
+    fn ior(&self, ray: &Ray) -> f32 {
+        match (&self.dispersion, ray.wavelengths) {
+            (Some(dispersion), Some(wavelengths)) => dispersion.ior(wavelengths.hero()),
+            _ => self.ref_idx,
+        }
+    }
+
+    fn entry(&self, ref_idx: f32) -> MediumEntry {
+        MediumEntry { id: self as *const Self as usize, priority: self.priority, ior: ref_idx, medium: self.medium.clone() }
+    }
+
+    // Inside a higher priority medium the surface is ignored and the ray
+    // continues as if nothing was hit
+    fn pass_false_hit(&self, ray: &Ray, hit: &HitRecord, ref_idx: f32) -> Option<Ray> {
+        let entry = self.entry(ref_idx);
+        let media = ray.media.clone().unwrap_or_default();
+        if !media.is_false_hit(entry.id, self.priority) {
+            return None;
+        }
+        let inside = if hit.front_face {
+            media.entered(entry)
+        } else {
+            media.exited(entry.id)
+        };
+        let mut passed = Ray::new(hit.p, ray.direction());
+        passed.media = Some(inside);
+        Some(passed)
+    }
 }
 
 impl Material for Dielectric {
     fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
-        let attenuation: Vector3<f32>;
-        let etai_over_etat = if hit.front_face {
-            1.0 / self.ref_idx
-        } else {
-            self.ref_idx
+        // Only the hero wavelength can follow a dispersed direction
+        let (ref_idx, wavelengths) = match (&self.dispersion, ray.wavelengths) {
+            (Some(dispersion), Some(wavelengths)) => (dispersion.ior(wavelengths.hero()), Some(wavelengths.terminate_secondary())),
+            _ => (self.ref_idx, ray.wavelengths),
         };
 
-        let normal = (hit.normal + self.roughness.value(hit.uv, hit.p).x * random_vec_in_unit_sphere()).normalize();
+        if let Some(passed) = self.pass_false_hit(ray, hit, ref_idx) {
+            return Some((passed, vec_one()));
+        }
 
-        let unit_direction = ray.direction().normalize();
-        let cos_theta = (-unit_direction).dot(&normal).min(1.0);
-        let sin_theta = (1.0 - cos_theta*cos_theta).sqrt();
+        let id = self as *const Self as usize;
+        let entry = self.entry(ref_idx);
+        let media = ray.media.clone().unwrap_or_default();
 
-        if !hit.front_face {
-            // Color
-            let distance = (ray.origin() - hit.p).magnitude();
-            attenuation = (-self.color.map(|x| 1.0/x) * self.density * distance).map(f32::exp);
+        // eta_transmitted / eta_incident, with the IOR of whatever is on the other side
+        let (eta, refracted_media) = if hit.front_face {
+            (ref_idx / media.ior(), media.entered(entry))
         } else {
-            attenuation = vec(1.0, 1.0, 1.0);
+            let outside = media.left(id);
+            (outside.ior() / ref_idx, outside)
+        };
+
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        let distribution = Ggx::from_roughness(self.roughness.value(hit.uv, hit.p).x, 0.0);
+        let (wi, weight) = sample_dielectric(wo, eta, &distribution, &mut thread_rng())?;
+
+        let kind = if distribution.is_smooth() { RayKind::Specular } else { RayKind::Glossy };
+        let mut scattered = Ray::new(hit.p, frame.to_world(wi)).with_kind(kind);
+        scattered.wavelengths = wavelengths;
+        scattered.media = Some(if wi.z < 0.0 { refracted_media } else { media });
+        Some((scattered, vec_one() * weight))
+    }
+
+    fn shadow_pass(&self, ray: &Ray, hit: &HitRecord) -> Option<Ray> {
+        self.pass_false_hit(ray, hit, self.ior(ray))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        let distribution = Ggx::from_roughness(self.roughness.value(hit.uv, hit.p).x, 0.0);
+        let id = self as *const Self as usize;
+        let media = ray.media.clone().unwrap_or_default();
+        // Smooth glass and surfaces hidden inside a higher priority medium
+        // can't be reached by light sampling
+        if distribution.is_smooth() || media.is_false_hit(id, self.priority) {
+            return None;
         }
 
-        let scattered = if etai_over_etat * sin_theta > 1.0 {
-            let reflected = reflect(unit_direction, normal);
-            Ray::new(hit.p, reflected)
+        let ref_idx = self.ior(ray);
+        let eta = if hit.front_face {
+            ref_idx / media.ior()
         } else {
-            let reflect_prob = schlick(cos_theta, self.ref_idx);
-            let mut rng = thread_rng();
-            let refracted_or_reflected = if rng.gen::<f32>() < reflect_prob {
-                reflect(unit_direction, normal)
-            } else {                                
-                refract(unit_direction, normal, etai_over_etat)
-            };
-            Ray::new(hit.p, refracted_or_reflected)
+            media.left(id).ior() / ref_idx
         };
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        let wi = frame.to_local(direction.normalize());
+        let (value, pdf) = eval_dielectric(wo, wi, eta, &distribution);
+        Some((vec_one() * value, pdf))
+    }
 
-        Some((scattered, attenuation))
+    // At the hero wavelength like scatter, the base IOR for rays without one
+    fn medium_entry(&self, ray: &Ray, _media: &MediumStack) -> Option<MediumEntry> {
+        Some(self.entry(self.ior(ray)))
     }
 }
 
@@ -112,15 +295,19 @@ impl Default for Dielectric {
     fn default() -> Dielectric {
         Dielectric {
             ref_idx: 1.52,
-            color: vec(1.0, 1.0, 1.0),
+            dispersion: None,
+            priority: 0,
             roughness: Arc::new(ConstantTex { color: vec_zero() }),
-            density: 0.0 //TODO: rename to absorption coefficient or something like that
+            medium: None,
         }
     }
 }
 
+// Emits from the front face only unless two_sided, see light.rs for lights
+// that can be sampled
 pub struct DiffuseLight {
-    pub emit: Arc<dyn Texture>
+    pub emit: Arc<dyn Texture>,
+    pub two_sided: bool,
 }
 
 impl Material for DiffuseLight {
@@ -129,6 +316,9 @@ impl Material for DiffuseLight {
     }
 
     fn emitted(&self, _ray: &Ray, hit: &HitRecord) -> Vector3<f32> {
+        if !hit.front_face && !self.two_sided {
+            return vec_zero();
+        }
         self.emit.value(hit.uv, hit.p)
     }
 }
@@ -161,6 +351,7 @@ impl EnvironmentMaterial for Environment {
     }
 }
 
+// Uniform scattering in all directions, see phase.rs for other phase functions
 pub struct Isotropic {
     pub albedo: Arc<dyn Texture>
 }
@@ -168,13 +359,17 @@ pub struct Isotropic {
 impl Material for Isotropic {
     fn scatter(&self, _ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
         Some((
-            Ray::new(hit.p, random_vec_in_unit_sphere()), 
+            Ray::new(hit.p, random_unit_vec()).with_kind(RayKind::Diffuse),
             self.albedo.value(hit.uv, hit.p)
         ))
     }
     fn is_solid(&self) -> bool {
         false
     }
+    fn eval(&self, _ray: &Ray, hit: &HitRecord, _direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        let pdf = 1.0 / (4.0 * f32::consts::PI);
+        Some((self.albedo.value(hit.uv, hit.p) * pdf, pdf))
+    }
 }
 
 fn get_sphere_uv(p: Vector3<f32>) -> Vector2<f32> {
@@ -187,44 +382,71 @@ fn get_sphere_uv(p: Vector3<f32>) -> Vector2<f32> {
 
 
 
+// Diffuse base under a dielectric interface, like varnished or glazed
+// surfaces. The interface reflects with a GGX lobe, roughness is read from the
+// x channel of the texture, and what it would refract is scattered by a
+// Lambertian base instead. The base is picked with the Fresnel transmittance
+// of the macro surface, so both lobes can be evaluated exactly.
 pub struct DielectricSurfaceLambert {
     pub ref_idx: f32,
     pub albedo: Arc<dyn Texture>,
     pub roughness: Arc<dyn Texture>,
 }
 
+impl DielectricSurfaceLambert {
+    fn interface(&self, hit: &HitRecord) -> (f32, Ggx) {
+        let eta = if hit.front_face { self.ref_idx } else { 1.0 / self.ref_idx };
+        (eta, Ggx::from_roughness(self.roughness.value(hit.uv, hit.p).x, 0.0))
+    }
+}
+
 impl Material for DielectricSurfaceLambert {
     fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
-        let mut attenuation = vec_one();
-        let etai_over_etat = if hit.front_face {
-            1.0 / self.ref_idx
-        } else {
-            self.ref_idx
-        };
-
-        let normal = (hit.normal + self.roughness.value(hit.uv, hit.p).x * random_vec_in_unit_sphere()).normalize();
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let (eta, distribution) = self.interface(hit);
+        let mut rng = thread_rng();
 
-        let unit_direction = ray.direction().normalize();
-        let cos_theta = (-unit_direction).dot(&normal).min(1.0);
-        let sin_theta = (1.0 - cos_theta*cos_theta).sqrt();
+        let reflect_prob = fresnel_dielectric(wo.z, eta);
+        if rng.gen::<f32>() >= reflect_prob {
+            let scattered = Ray::new(hit.p, frame.to_world(random_cosine_direction())).with_kind(RayKind::Diffuse);
+            return Some((scattered, self.albedo.value(hit.uv, hit.p)));
+        }
 
-        let scattered = if etai_over_etat * sin_theta > 1.0 {
-            let reflected = reflect(unit_direction, normal);
-            Ray::new(hit.p, reflected)
-        } else {
-            let reflect_prob = schlick(cos_theta, self.ref_idx);
-            let mut rng = thread_rng();
-            let refracted_or_reflected = if rng.gen::<f32>() < reflect_prob {
-                reflect(unit_direction, normal)
-            } else {                                
-                // Instead of refracting we fo Lambertian               
-                attenuation = self.albedo.value(hit.uv, hit.p);
-                hit.normal + random_unit_vec()
-            };
-            Ray::new(hit.p, refracted_or_reflected)
-        };
+        if distribution.is_smooth() {
+            let wi = vec(-wo.x, -wo.y, wo.z);
+            return Some((Ray::new(hit.p, frame.to_world(wi)).with_kind(RayKind::Specular), vec_one()));
+        }
+        let m = distribution.sample_visible_normal(wo, rng.gen::<f32>(), rng.gen::<f32>());
+        let wi = reflect_local(wo, m);
+        if wi.z <= 0.0 {
+            return None;
+        }
+        let weight = fresnel_dielectric(wo.dot(&m), eta) / reflect_prob * distribution.g(wo, wi) / distribution.g1(wo);
+        Some((Ray::new(hit.p, frame.to_world(wi)).with_kind(RayKind::Glossy), vec_one() * weight))
+    }
 
-        Some((scattered, attenuation))
+    fn eval(&self, ray: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        let wi = frame.to_local(direction.normalize());
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Some((vec_zero(), 0.0));
+        }
+        let (eta, distribution) = self.interface(hit);
+        let reflect_prob = fresnel_dielectric(wo.z, eta);
+
+        let diffuse_pdf = (1.0 - reflect_prob) * wi.z / f32::consts::PI;
+        let mut value = self.albedo.value(hit.uv, hit.p) * diffuse_pdf;
+        let mut pdf = diffuse_pdf;
+        if let Some((m, lobe, lobe_pdf)) = eval_reflection(wo, wi, &distribution) {
+            value += vec_one() * (fresnel_dielectric(wo.dot(&m), eta) * lobe);
+            pdf += reflect_prob * lobe_pdf;
+        }
+        Some((value, pdf))
     }
 }
 
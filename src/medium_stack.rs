@@ -0,0 +1,163 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::volume::Medium;
+
+// Stack of the dielectric objects a ray is currently inside, for nested
+// dielectrics (Schmidt and Budge 2002, "Simple Nested Dielectrics in Ray
+// Traced Images"). Every object has a priority and where objects overlap the
+// one with the highest priority defines the medium, so liquid in a glass only
+// needs to overlap the glass a little and the lower priority surface inside
+// the overlap is skipped as a false hit.
+//
+// Objects are identified by their material: objects sharing one
+// Arc<Dielectric> are merged into a single medium, so the inner wall of
+// hollow glass should use the same material as the outer one. An entry can
+// also carry the interior medium of its object, which the integrator samples
+// while the entry is current. Volumes overlapping at the current priority are
+// all sampled together.
+
+pub const MAX_NESTED_MEDIA: usize = 8;
+// Index of refraction outside of every object
+pub const AIR_IOR: f32 = 1.0;
+
+#[derive(Clone, Default)]
+pub struct MediumEntry {
+    pub id: usize,
+    pub priority: u32,
+    pub ior: f32,
+    pub medium: Option<Arc<dyn Medium>>,
+}
+
+impl fmt::Debug for MediumEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MediumEntry {{ id: {:#x}, priority: {}, ior: {}, medium: {} }}",
+            self.id, self.priority, self.ior, self.medium.is_some())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MediumStack {
+    entries: [MediumEntry; MAX_NESTED_MEDIA],
+    len: usize,
+}
+
+impl Default for MediumStack {
+    fn default() -> Self {
+        MediumStack {
+            entries: std::array::from_fn(|_| MediumEntry::default()),
+            len: 0,
+        }
+    }
+}
+
+impl MediumStack {
+    pub fn entries(&self) -> &[MediumEntry] {
+        &self.entries[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.entries().iter().any(|e| e.id == id)
+    }
+
+    // The medium the ray is travelling through, the most recently entered one
+    // wins between equal priorities
+    pub fn current(&self) -> Option<&MediumEntry> {
+        self.entries().iter().enumerate().max_by_key(|(i, e)| (e.priority, *i)).map(|(_, e)| e)
+    }
+
+    pub fn ior(&self) -> f32 {
+        self.current().map_or(AIR_IOR, |e| e.ior)
+    }
+
+    // Interior media of every entry at the current priority, so overlapping
+    // volumes add up instead of the last one entered hiding the others
+    pub fn media(&self) -> Vec<Arc<dyn Medium>> {
+        let priority = match self.current() {
+            Some(current) => current.priority,
+            None => return Vec::new(),
+        };
+        self.entries().iter()
+            .filter(|e| e.priority == priority)
+            .filter_map(|e| e.medium.clone())
+            .collect()
+    }
+
+    // Stack after entering an object. When the stack is full the innermost
+    // object is dropped, which only happens with absurdly deep nesting.
+    pub fn entered(&self, entry: MediumEntry) -> Self {
+        let mut stack = self.exited(entry.id);
+        if stack.len == MAX_NESTED_MEDIA {
+            stack.entries.rotate_left(1);
+            stack.len -= 1;
+        }
+        stack.entries[stack.len] = entry;
+        stack.len += 1;
+        stack
+    }
+
+    pub fn exited(&self, id: usize) -> Self {
+        let mut stack = MediumStack::default();
+        for e in self.entries().iter().filter(|e| e.id != id) {
+            stack.entries[stack.len] = e.clone();
+            stack.len += 1;
+        }
+        stack
+    }
+
+    // Stack after passing out through a surface of object id. A back face of
+    // an object the ray never entered, like the inner wall of hollow glass
+    // with its own material, leaves the medium the ray is currently in.
+    pub fn left(&self, id: usize) -> Self {
+        if self.contains(id) {
+            return self.exited(id);
+        }
+        match self.current() {
+            Some(current) => self.exited(current.id),
+            None => self.clone(),
+        }
+    }
+
+    // A surface is only a real interface when its object has the highest
+    // priority at that point, otherwise it lies inside a higher priority medium
+    pub fn is_false_hit(&self, id: usize, priority: u32) -> bool {
+        self.exited(id).current().is_some_and(|e| e.priority > priority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: usize, priority: u32, ior: f32) -> MediumEntry {
+        MediumEntry { id, priority, ior, medium: None }
+    }
+
+    #[test]
+    fn latest_entry_wins_between_equal_priorities() {
+        let fog_then_glass = MediumStack::default().entered(entry(1, 0, 1.0)).entered(entry(2, 0, 1.5));
+        assert_eq!(fog_then_glass.ior(), 1.5);
+        let glass_then_fog = MediumStack::default().entered(entry(2, 0, 1.5)).entered(entry(1, 0, 1.0));
+        assert_eq!(glass_then_fog.ior(), 1.0);
+    }
+
+    #[test]
+    fn higher_priority_wins() {
+        let media = MediumStack::default().entered(entry(1, 2, 1.33)).entered(entry(2, 1, 1.5));
+        assert_eq!(media.ior(), 1.33);
+        assert!(media.is_false_hit(2, 1));
+    }
+
+    #[test]
+    fn leaving_an_object_that_was_never_entered() {
+        let media = MediumStack::default().entered(entry(1, 0, 1.0)).entered(entry(2, 0, 1.5));
+        let outside = media.left(3);
+        assert!(!outside.contains(2));
+        assert_eq!(outside.ior(), 1.0);
+        assert!(MediumStack::default().left(3).is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+use nalgebra::Vector3;
+use rand::Rng;
+use std::f32;
+
+use crate::vec::vec3;
+
+// Orthonormal basis around a normal, microfacet math is done in this local
+// frame where the normal is +z. (Duff et al. 2017, "Building an Orthonormal
+// Basis, Revisited")
+pub struct Frame {
+    pub s: Vector3<f32>,
+    pub t: Vector3<f32>,
+    pub n: Vector3<f32>,
+}
+
+impl Frame {
+    pub fn from_normal(n: Vector3<f32>) -> Self {
+        let n = n.normalize();
+        let sign = 1f32.copysign(n.z);
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+        Frame {
+            s: vec3(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+            t: vec3(b, sign + n.y * n.y * a, -n.y),
+            n,
+        }
+    }
+
+    pub fn to_local(&self, v: Vector3<f32>) -> Vector3<f32> {
+        vec3(v.dot(&self.s), v.dot(&self.t), v.dot(&self.n))
+    }
+
+    pub fn to_world(&self, v: Vector3<f32>) -> Vector3<f32> {
+        v.x * self.s + v.y * self.t + v.z * self.n
+    }
+}
+
+// Trowbridge-Reitz (GGX) microfacet distribution, optionally anisotropic
+#[derive(Clone, Copy, Debug)]
+pub struct Ggx {
+    pub alpha_x: f32,
+    pub alpha_y: f32,
+}
+
+impl Ggx {
+    // Perceptual roughness in [0, 1] to alpha, anisotropy in [0, 1) stretches
+    // the highlight along the tangent (same mapping as the Disney BRDF). Hits
+    // carry no dpdu, so the tangent is the s axis Frame::from_normal picks: it
+    // turns smoothly with the normal but doesn't follow the uv layout, e.g.
+    // brushed metal can't be aligned with a texture.
+    pub fn from_roughness(roughness: f32, anisotropy: f32) -> Self {
+        let alpha = (roughness * roughness).max(1e-4);
+        let aspect = (1.0 - 0.9 * anisotropy).sqrt();
+        Ggx {
+            alpha_x: (alpha / aspect).max(1e-4),
+            alpha_y: (alpha * aspect).max(1e-4),
+        }
+    }
+
+    // Below this the surface is treated as perfectly smooth
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < 1e-3
+    }
+
+    pub fn d(&self, m: Vector3<f32>) -> f32 {
+        if m.z <= 0.0 {
+            return 0.0;
+        }
+        let x = m.x / self.alpha_x;
+        let y = m.y / self.alpha_y;
+        let denom = x * x + y * y + m.z * m.z;
+        1.0 / (f32::consts::PI * self.alpha_x * self.alpha_y * denom * denom)
+    }
+
+    pub fn lambda(&self, w: Vector3<f32>) -> f32 {
+        if w.z == 0.0 {
+            return f32::INFINITY;
+        }
+        let a2 = (self.alpha_x * w.x).powi(2) + (self.alpha_y * w.y).powi(2);
+        ((1.0 + a2 / (w.z * w.z)).sqrt() - 1.0) / 2.0
+    }
+
+    pub fn g1(&self, w: Vector3<f32>) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Height-correlated masking-shadowing
+    pub fn g(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Samples a microfacet normal visible from `wo` (Heitz 2018, "Sampling the
+    // GGX Distribution of Visible Normals"). `wo` has to be in the upper hemisphere.
+    pub fn sample_visible_normal(&self, wo: Vector3<f32>, u1: f32, u2: f32) -> Vector3<f32> {
+        let vh = vec3(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).normalize();
+        let lensq = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if lensq > 0.0 {
+            vec3(-vh.y, vh.x, 0.0) / lensq.sqrt()
+        } else {
+            vec3(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(&t1);
+
+        let r = u1.sqrt();
+        let phi = 2.0 * f32::consts::PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+
+        let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+        vec3(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)).normalize()
+    }
+
+    // Density of `sample_visible_normal` returning m
+    pub fn pdf_visible_normal(&self, wo: Vector3<f32>, m: Vector3<f32>) -> f32 {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(&m).max(0.0) * self.d(m) / wo.z
+    }
+}
+
+pub fn reflect_local(wo: Vector3<f32>, m: Vector3<f32>) -> Vector3<f32> {
+    2.0 * wo.dot(&m) * m - wo
+}
+
+// Refracts `wo` through the microfacet `m` on the same side as `wo`, eta is
+// eta_transmitted / eta_incident. None on total internal reflection.
+pub fn refract_local(wo: Vector3<f32>, m: Vector3<f32>, eta: f32) -> Option<Vector3<f32>> {
+    let cos_i = wo.dot(&m);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + (cos_i / eta - cos_t) * m)
+}
+
+// Unpolarized Fresnel reflectance of a dielectric interface, eta is
+// eta_transmitted / eta_incident and cos_i is measured on the incident side
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parl = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parl * r_parl + r_perp * r_perp) / 2.0
+}
+
+// Schlick's approximation with a colored reflectance at normal incidence
+pub fn schlick_color(f0: Vector3<f32>, cos_i: f32) -> Vector3<f32> {
+    let w = (1.0 - cos_i.clamp(0.0, 1.0)).powi(5);
+    f0 + (Vector3::new(1.0, 1.0, 1.0) - f0) * w
+}
+
+// Fresnel reflectance of a conductor with complex index of refraction eta + ik,
+// evaluated per color channel
+pub fn fresnel_conductor(cos_i: f32, eta: Vector3<f32>, k: Vector3<f32>) -> Vector3<f32> {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    eta.zip_map(&k, |eta, k| {
+        let cos2 = cos_i * cos_i;
+        let sin2 = 1.0 - cos2;
+        let eta2 = eta * eta;
+        let k2 = k * k;
+
+        let t0 = eta2 - k2 - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+        let t2 = 2.0 * cos_i * a;
+        let rs = (t1 - t2) / (t1 + t2);
+
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+
+        0.5 * (rp + rs)
+    })
+}
+
+// Samples a rough dielectric interface (Walter et al. 2007, "Microfacet Models
+// for Refraction through Rough Surfaces") in the local frame of the side `wo` is
+// on. eta is eta_transmitted / eta_incident. Reflection and refraction are
+// chosen with the Fresnel term, so with visible normal sampling the path weight
+// reduces to G2 / G1. Returns the incoming direction and that weight.
+pub fn sample_dielectric(wo: Vector3<f32>, eta: f32, distribution: &Ggx, rng: &mut impl Rng) -> Option<(Vector3<f32>, f32)> {
+    if wo.z <= 0.0 {
+        return None;
+    }
+    let smooth = distribution.is_smooth();
+    let m = if smooth {
+        vec3(0.0, 0.0, 1.0)
+    } else {
+        distribution.sample_visible_normal(wo, rng.gen::<f32>(), rng.gen::<f32>())
+    };
+
+    // Total internal reflection gives F = 1 and always reflects
+    let fresnel = fresnel_dielectric(wo.dot(&m), eta);
+    let refracted = if rng.gen::<f32>() < fresnel { None } else { refract_local(wo, m, eta) };
+
+    let wi = match refracted {
+        Some(wt) => {
+            if wt.z >= 0.0 {
+                return None;
+            }
+            wt
+        }
+        None => {
+            let wr = reflect_local(wo, m);
+            if wr.z <= 0.0 {
+                return None;
+            }
+            wr
+        }
+    };
+
+    let weight = if smooth { 1.0 } else { distribution.g(wo, wi) / distribution.g1(wo) };
+    Some((wi, weight))
+}
+
+// GGX reflection from wo to wi without the Fresnel term, the counterpart of
+// sampling a visible normal and reflecting: the microfacet normal,
+// D G / (4 cos_o) and the density of wi. Their ratio is the G2 / G1 weight.
+// None for smooth surfaces, which can't be evaluated.
+pub fn eval_reflection(wo: Vector3<f32>, wi: Vector3<f32>, distribution: &Ggx) -> Option<(Vector3<f32>, f32, f32)> {
+    if wo.z <= 0.0 || wi.z <= 0.0 || distribution.is_smooth() {
+        return None;
+    }
+    let m = (wo + wi).try_normalize(1e-8)?;
+    let d = distribution.d(m);
+    Some((m, d * distribution.g(wo, wi) / (4.0 * wo.z), distribution.g1(wo) * d / (4.0 * wo.z)))
+}
+
+// Counterpart of sample_dielectric: f times |cos wi| and the density of
+// sampling wi, their ratio is the weight sample_dielectric returns. Zero for
+// smooth surfaces.
+pub fn eval_dielectric(wo: Vector3<f32>, wi: Vector3<f32>, eta: f32, distribution: &Ggx) -> (f32, f32) {
+    if wi.z > 0.0 {
+        return match eval_reflection(wo, wi, distribution) {
+            Some((m, value, pdf)) => {
+                let fresnel = fresnel_dielectric(wo.dot(&m), eta);
+                (fresnel * value, fresnel * pdf)
+            }
+            None => (0.0, 0.0),
+        };
+    }
+    if wo.z <= 0.0 || wi.z == 0.0 || distribution.is_smooth() {
+        return (0.0, 0.0);
+    }
+
+    // Generalized half vector, facing wo's side
+    let m = match (wo + eta * wi).try_normalize(1e-8) {
+        Some(m) if m.z < 0.0 => -m,
+        Some(m) => m,
+        None => return (0.0, 0.0),
+    };
+    let (cos_o, cos_i) = (wo.dot(&m), wi.dot(&m));
+    if cos_o <= 0.0 || cos_i >= 0.0 {
+        return (0.0, 0.0);
+    }
+    let transmission = 1.0 - fresnel_dielectric(cos_o, eta);
+    let d = distribution.d(m);
+    // Change of variables from the microfacet normal to wi
+    let jacobian = eta * eta * -cos_i / (cos_o + eta * cos_i).powi(2);
+    let pdf = transmission * distribution.g1(wo) * cos_o * d / wo.z * jacobian;
+    (pdf * distribution.g(wo, wi) / distribution.g1(wo), pdf)
+}
@@ -0,0 +1,145 @@
+use nalgebra::Vector3;
+use rand::{thread_rng, Rng};
+use std::{f32, sync::Arc};
+
+use crate::hittable::HitRecord;
+use crate::material::Material;
+use crate::microfacet::Frame;
+use crate::ray::{Ray, RayKind};
+use crate::texture::Texture;
+use crate::vec::{vec2, vec3};
+
+// Phase functions describe how light scatters inside a medium. Directions are
+// unit propagation directions, so for d_out == d_in the light keeps going
+// straight (cos_theta = 1). All of these are sampled exactly, which makes the
+// sampling weight one and the pdf equal to p.
+pub trait PhaseFunction: Sync + Send {
+    fn p(&self, d_in: Vector3<f32>, d_out: Vector3<f32>, point: Vector3<f32>) -> f32;
+    fn sample(&self, d_in: Vector3<f32>, point: Vector3<f32>, u1: f32, u2: f32) -> Vector3<f32>;
+
+    fn pdf(&self, d_in: Vector3<f32>, d_out: Vector3<f32>, point: Vector3<f32>) -> f32 {
+        self.p(d_in, d_out, point)
+    }
+}
+
+// Direction at angle acos(cos_theta) from `d_in`, rotated by 2 pi u around it
+fn around(d_in: Vector3<f32>, cos_theta: f32, u: f32) -> Vector3<f32> {
+    let cos_theta = cos_theta.clamp(-1.0, 1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * f32::consts::PI * u;
+    Frame::from_normal(d_in).to_world(vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+}
+
+pub struct IsotropicPhase;
+
+impl PhaseFunction for IsotropicPhase {
+    fn p(&self, _d_in: Vector3<f32>, _d_out: Vector3<f32>, _point: Vector3<f32>) -> f32 {
+        1.0 / (4.0 * f32::consts::PI)
+    }
+
+    fn sample(&self, d_in: Vector3<f32>, _point: Vector3<f32>, u1: f32, u2: f32) -> Vector3<f32> {
+        around(d_in, 1.0 - 2.0 * u1, u2)
+    }
+}
+
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * f32::consts::PI * denom * denom.max(1e-8).sqrt())
+}
+
+fn sample_henyey_greenstein(d_in: Vector3<f32>, g: f32, u1: f32, u2: f32) -> Vector3<f32> {
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u1
+    } else {
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+        (1.0 + g * g - s * s) / (2.0 * g)
+    };
+    around(d_in, cos_theta, u2)
+}
+
+// Henyey-Greenstein, g in (-1, 1) is read from the x channel of the texture at
+// the scattering point. Positive g scatters forward, negative backward.
+pub struct HenyeyGreenstein {
+    pub g: Arc<dyn Texture>,
+}
+
+impl HenyeyGreenstein {
+    fn g(&self, point: Vector3<f32>) -> f32 {
+        self.g.value(vec2(0.0, 0.0), point).x.clamp(-0.99, 0.99)
+    }
+}
+
+impl PhaseFunction for HenyeyGreenstein {
+    fn p(&self, d_in: Vector3<f32>, d_out: Vector3<f32>, point: Vector3<f32>) -> f32 {
+        henyey_greenstein(d_in.dot(&d_out), self.g(point))
+    }
+
+    fn sample(&self, d_in: Vector3<f32>, point: Vector3<f32>, u1: f32, u2: f32) -> Vector3<f32> {
+        sample_henyey_greenstein(d_in, self.g(point), u1, u2)
+    }
+}
+
+// Blend of a forward and a backward lobe, e.g. for clouds. `weight` is the
+// fraction of the first lobe.
+pub struct DoubleHenyeyGreenstein {
+    pub g1: f32,
+    pub g2: f32,
+    pub weight: f32,
+}
+
+impl PhaseFunction for DoubleHenyeyGreenstein {
+    fn p(&self, d_in: Vector3<f32>, d_out: Vector3<f32>, _point: Vector3<f32>) -> f32 {
+        let cos_theta = d_in.dot(&d_out);
+        self.weight * henyey_greenstein(cos_theta, self.g1) + (1.0 - self.weight) * henyey_greenstein(cos_theta, self.g2)
+    }
+
+    fn sample(&self, d_in: Vector3<f32>, _point: Vector3<f32>, u1: f32, u2: f32) -> Vector3<f32> {
+        // Reuse u1 for the lobe selection
+        if u1 < self.weight {
+            sample_henyey_greenstein(d_in, self.g1, u1 / self.weight, u2)
+        } else {
+            sample_henyey_greenstein(d_in, self.g2, (u1 - self.weight) / (1.0 - self.weight), u2)
+        }
+    }
+}
+
+// Scattering by particles much smaller than the wavelength, like air molecules
+pub struct Rayleigh;
+
+impl PhaseFunction for Rayleigh {
+    fn p(&self, d_in: Vector3<f32>, d_out: Vector3<f32>, _point: Vector3<f32>) -> f32 {
+        let cos_theta = d_in.dot(&d_out);
+        3.0 / (16.0 * f32::consts::PI) * (1.0 + cos_theta * cos_theta)
+    }
+
+    fn sample(&self, d_in: Vector3<f32>, _point: Vector3<f32>, u1: f32, u2: f32) -> Vector3<f32> {
+        // Inverting the cdf (cos^3 + 3 cos + 4) / 8 = u1 with Cardano's formula
+        let z = 4.0 * u1 - 2.0;
+        let r = (z * z + 1.0).sqrt();
+        around(d_in, (z + r).cbrt() + (z - r).cbrt(), u2)
+    }
+}
+
+// Scattering point material for media, like Isotropic but with any phase function
+pub struct PhaseMaterial {
+    pub albedo: Arc<dyn Texture>,
+    pub phase_function: Arc<dyn PhaseFunction>,
+}
+
+impl Material for PhaseMaterial {
+    fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
+        let mut rng = thread_rng();
+        let d_out = self.phase_function.sample(ray.direction().normalize(), hit.p, rng.gen::<f32>(), rng.gen::<f32>());
+        Some((Ray::new(hit.p, d_out).with_kind(RayKind::Diffuse), self.albedo.value(hit.uv, hit.p)))
+    }
+
+    fn is_solid(&self) -> bool {
+        false
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        let (d_in, d_out) = (ray.direction().normalize(), direction.normalize());
+        let p = self.phase_function.p(d_in, d_out, hit.p);
+        Some((self.albedo.value(hit.uv, hit.p) * p, self.phase_function.pdf(d_in, d_out, hit.p)))
+    }
+}
@@ -0,0 +1,157 @@
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Window};
+use nalgebra::Vector3;
+
+use crate::camera::Camera;
+use crate::utils::clamp;
+
+// Controls:
+//   left drag: orbit, right drag: pan, scroll / middle drag: dolly
+//   Z / X: narrower / wider field of view
+//   F / G: focus distance closer / further, R: focus on the look-at point
+//   [ / ]: smaller / larger aperture
+//   1-4: beauty, albedo, normal and sample heatmap views
+//   - / =: exposure down / up, O: toggle the overlay
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugView {
+    Beauty,
+    Albedo,
+    Normal,
+    Heatmap,
+}
+
+impl DebugView {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DebugView::Beauty => "beauty",
+            DebugView::Albedo => "albedo",
+            DebugView::Normal => "normal",
+            DebugView::Heatmap => "heatmap",
+        }
+    }
+}
+
+pub struct Preview {
+    pub view: DebugView,
+    // In stops
+    pub exposure: f32,
+    pub show_overlay: bool,
+    last_mouse: Option<(f32, f32)>,
+}
+
+impl Preview {
+    pub fn new() -> Self {
+        Preview {
+            view: DebugView::Beauty,
+            exposure: 0.0,
+            show_overlay: true,
+            last_mouse: None,
+        }
+    }
+
+    // Applies camera navigation, returns true when the camera changed and the
+    // accumulated samples are no longer valid.
+    pub fn handle_camera_input(&mut self, window: &Window, cam: &mut Camera) -> bool {
+        let mut changed = false;
+        let (_, height) = window.get_size();
+
+        let mouse = window.get_mouse_pos(MouseMode::Pass);
+        if let (Some((x, y)), Some((last_x, last_y))) = (mouse, self.last_mouse) {
+            let dx = x - last_x;
+            let dy = y - last_y;
+            if dx != 0.0 || dy != 0.0 {
+                if window.get_mouse_down(MouseButton::Left) {
+                    cam.orbit(-dx * 0.3, dy * 0.3);
+                    changed = true;
+                } else if window.get_mouse_down(MouseButton::Right) {
+                    cam.pan(-dx / height as f32, dy / height as f32);
+                    changed = true;
+                } else if window.get_mouse_down(MouseButton::Middle) {
+                    cam.dolly(dy / height as f32);
+                    changed = true;
+                }
+            }
+        }
+        self.last_mouse = mouse;
+
+        if let Some((_, scroll)) = window.get_scroll_wheel() {
+            if scroll != 0.0 {
+                cam.dolly(0.05 * scroll.signum());
+                changed = true;
+            }
+        }
+
+        let mut view_changed = false;
+        if window.is_key_pressed(Key::Z, KeyRepeat::Yes) {
+            cam.vfov = (cam.vfov - 2.0).max(1.0);
+            view_changed = true;
+        }
+        if window.is_key_pressed(Key::X, KeyRepeat::Yes) {
+            cam.vfov = (cam.vfov + 2.0).min(170.0);
+            view_changed = true;
+        }
+        if window.is_key_pressed(Key::F, KeyRepeat::Yes) {
+            cam.focus_dist *= 0.95;
+            view_changed = true;
+        }
+        if window.is_key_pressed(Key::G, KeyRepeat::Yes) {
+            cam.focus_dist *= 1.05;
+            view_changed = true;
+        }
+        if window.is_key_pressed(Key::R, KeyRepeat::No) {
+            cam.focus_dist = (cam.origin - cam.lookat).magnitude();
+            view_changed = true;
+        }
+        let aperture_step = 0.01 * cam.focus_dist;
+        if window.is_key_pressed(Key::LeftBracket, KeyRepeat::Yes) {
+            cam.set_aperture(cam.aperture() - aperture_step);
+            view_changed = true;
+        }
+        if window.is_key_pressed(Key::RightBracket, KeyRepeat::Yes) {
+            cam.set_aperture(cam.aperture() + aperture_step);
+            view_changed = true;
+        }
+        if view_changed {
+            cam.update();
+        }
+
+        changed || view_changed
+    }
+
+    // Display-only settings, these never invalidate the accumulated samples
+    pub fn handle_toggles(&mut self, window: &Window) {
+        if window.is_key_pressed(Key::Key1, KeyRepeat::No) {
+            self.view = DebugView::Beauty;
+        }
+        if window.is_key_pressed(Key::Key2, KeyRepeat::No) {
+            self.view = DebugView::Albedo;
+        }
+        if window.is_key_pressed(Key::Key3, KeyRepeat::No) {
+            self.view = DebugView::Normal;
+        }
+        if window.is_key_pressed(Key::Key4, KeyRepeat::No) {
+            self.view = DebugView::Heatmap;
+        }
+        if window.is_key_pressed(Key::Minus, KeyRepeat::Yes) {
+            self.exposure -= 0.5;
+        }
+        if window.is_key_pressed(Key::Equal, KeyRepeat::Yes) {
+            self.exposure += 0.5;
+        }
+        if window.is_key_pressed(Key::O, KeyRepeat::No) {
+            self.show_overlay = !self.show_overlay;
+        }
+    }
+
+    // Exposure and the sqrt tone curve, packed as 0RGB
+    pub fn tonemap(&self, col: Vector3<f32>) -> u32 {
+        let scale = 2f32.powf(self.exposure);
+        let to_u8 = |c: f32| clamp((c * scale).sqrt() * 255.99, 0.0, 255.0) as u32;
+        (to_u8(col.x) << 16) | (to_u8(col.y) << 8) | to_u8(col.z)
+    }
+}
+
+// Debug views that show data directly, without tone mapping
+pub fn pack_color(col: Vector3<f32>) -> u32 {
+    let to_u8 = |c: f32| clamp(c * 255.99, 0.0, 255.0) as u32;
+    (to_u8(col.x) << 16) | (to_u8(col.y) << 8) | to_u8(col.z)
+}
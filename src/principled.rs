@@ -0,0 +1,223 @@
+use nalgebra::Vector3;
+use rand::{thread_rng, Rng};
+use std::{f32, sync::Arc};
+
+use crate::hittable::HitRecord;
+use crate::material::Material;
+use crate::microfacet::{Frame, Ggx, eval_dielectric, eval_reflection, reflect_local, sample_dielectric, schlick_color};
+use crate::ray::{Ray, RayKind};
+use crate::texture::{ConstantTex, Texture};
+use crate::vec::{luminance, random_cosine_direction, vec, vec_one, vec_zero};
+
+// Disney-style principled material. Every parameter is a texture, scalar
+// parameters are read from the x channel.
+//
+// A single lobe is picked per scatter event: clearcoat (by its Fresnel
+// reflectance), then metal, glass or the opaque dielectric base (by metallic and
+// transmission), and for the opaque base either specular (by Fresnel) or
+// diffuse plus sheen. Dividing by the selection probability keeps the estimate
+// unbiased and light that is reflected by a layer never reaches the layers below.
+pub struct Principled {
+    pub base_color: Arc<dyn Texture>,
+    pub metallic: Arc<dyn Texture>,
+    pub roughness: Arc<dyn Texture>,
+    // Oriented like for Conductor, see Ggx::from_roughness
+    pub anisotropic: Arc<dyn Texture>,
+    // Scales the dielectric reflectance at normal incidence, 0.5 is 4%
+    pub specular: Arc<dyn Texture>,
+    pub specular_tint: Arc<dyn Texture>,
+    pub sheen: Arc<dyn Texture>,
+    pub sheen_tint: Arc<dyn Texture>,
+    pub clearcoat: Arc<dyn Texture>,
+    pub clearcoat_roughness: Arc<dyn Texture>,
+    pub transmission: Arc<dyn Texture>,
+    pub ior: Arc<dyn Texture>,
+    pub emission: Arc<dyn Texture>,
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        let scalar = |v: f32| ConstantTex::new_arc(vec(v, v, v));
+        Principled {
+            base_color: ConstantTex::new_arc(vec(0.8, 0.8, 0.8)),
+            metallic: scalar(0.0),
+            roughness: scalar(0.5),
+            anisotropic: scalar(0.0),
+            specular: scalar(0.5),
+            specular_tint: scalar(0.0),
+            sheen: scalar(0.0),
+            sheen_tint: scalar(0.5),
+            clearcoat: scalar(0.0),
+            clearcoat_roughness: scalar(0.03),
+            transmission: scalar(0.0),
+            ior: scalar(1.45),
+            emission: ConstantTex::new_arc(vec_zero()),
+        }
+    }
+}
+
+// Base color normalized to luminance 1, used to tint specular and sheen
+fn tint_color(base: Vector3<f32>) -> Vector3<f32> {
+    let lum = luminance(base);
+    if lum > 0.0 { base / lum } else { vec_one() }
+}
+
+fn lerp(a: Vector3<f32>, b: Vector3<f32>, t: f32) -> Vector3<f32> {
+    a * (1.0 - t) + b * t
+}
+
+// Samples a GGX reflection, returns the direction and G2 / G1
+fn sample_glossy(wo: Vector3<f32>, distribution: &Ggx, rng: &mut impl Rng) -> Option<(Vector3<f32>, Vector3<f32>, f32)> {
+    let m = if distribution.is_smooth() {
+        vec(0.0, 0.0, 1.0)
+    } else {
+        distribution.sample_visible_normal(wo, rng.gen::<f32>(), rng.gen::<f32>())
+    };
+    let wi = reflect_local(wo, m);
+    if wi.z <= 0.0 {
+        return None;
+    }
+    let weight = if distribution.is_smooth() { 1.0 } else { distribution.g(wo, wi) / distribution.g1(wo) };
+    Some((wi, m, weight))
+}
+
+impl Material for Principled {
+    fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
+        let (uv, p) = (hit.uv, hit.p);
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let mut rng = thread_rng();
+        let scattered = |wi: Vector3<f32>, weight: Vector3<f32>, kind: RayKind| Some((Ray::new(hit.p, frame.to_world(wi)).with_kind(kind), weight));
+        let reflection = |distribution: &Ggx| if distribution.is_smooth() { RayKind::Specular } else { RayKind::Glossy };
+
+        let base = self.base_color.value(uv, p);
+        let metallic = self.metallic.value(uv, p).x;
+        let roughness = self.roughness.value(uv, p).x;
+        let distribution = Ggx::from_roughness(roughness, self.anisotropic.value(uv, p).x);
+
+        // Clearcoat, a colorless layer with a fixed IOR of 1.5 on top of everything
+        let clearcoat = self.clearcoat.value(uv, p).x;
+        if clearcoat > 0.0 {
+            let f0 = vec(0.04, 0.04, 0.04);
+            let coat_prob = clearcoat * schlick_color(f0, wo.z).x;
+            if rng.gen::<f32>() < coat_prob {
+                let coat = Ggx::from_roughness(self.clearcoat_roughness.value(uv, p).x, 0.0);
+                let (wi, m, weight) = sample_glossy(wo, &coat, &mut rng)?;
+                let fresnel = schlick_color(f0, wo.dot(&m)).x / schlick_color(f0, wo.z).x;
+                return scattered(wi, vec_one() * (fresnel * weight), reflection(&coat));
+            }
+        }
+
+        if rng.gen::<f32>() < metallic {
+            let (wi, m, weight) = sample_glossy(wo, &distribution, &mut rng)?;
+            return scattered(wi, schlick_color(base, wo.dot(&m)) * weight, reflection(&distribution));
+        }
+
+        if rng.gen::<f32>() < self.transmission.value(uv, p).x {
+            // Solid glass, eta is inverted when the ray leaves the object
+            let ior = self.ior.value(uv, p).x;
+            let eta = if hit.front_face { ior } else { 1.0 / ior };
+            let (wi, weight) = sample_dielectric(wo, eta, &distribution, &mut rng)?;
+            let tint = if wi.z < 0.0 { base } else { vec_one() };
+            return scattered(wi, tint * weight, reflection(&distribution));
+        }
+
+        // Opaque dielectric, specular reflection over a diffuse base
+        let tint = tint_color(base);
+        let specular_f0 = lerp(vec_one(), tint, self.specular_tint.value(uv, p).x)
+            * (0.08 * self.specular.value(uv, p).x);
+        let specular_prob = luminance(schlick_color(specular_f0, wo.z)).clamp(0.0, 1.0);
+
+        if rng.gen::<f32>() < specular_prob {
+            let (wi, m, weight) = sample_glossy(wo, &distribution, &mut rng)?;
+            return scattered(wi, schlick_color(specular_f0, wo.dot(&m)) * (weight / specular_prob), reflection(&distribution));
+        }
+
+        let wi = random_cosine_direction();
+        let h = (wi + wo).normalize();
+        let sheen_color = lerp(vec_one(), tint, self.sheen_tint.value(uv, p).x);
+        let sheen = sheen_color * (self.sheen.value(uv, p).x * (1.0 - wi.dot(&h).clamp(0.0, 1.0)).powi(5));
+        // Cosine sampling cancels the 1 / pi of the diffuse lobe, the sheen lobe has no 1 / pi
+        let weight = base + f32::consts::PI * sheen;
+        scattered(wi, weight, RayKind::Diffuse)
+    }
+
+    // Sum over the non-delta lobes of selection probability times lobe value,
+    // with the same probabilities as scatter
+    fn eval(&self, ray: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        let (uv, p) = (hit.uv, hit.p);
+        let frame = Frame::from_normal(hit.normal);
+        let wo = frame.to_local(-ray.direction().normalize());
+        let wi = frame.to_local(direction.normalize());
+        if wo.z <= 0.0 {
+            return Some((vec_zero(), 0.0));
+        }
+        let mut value = vec_zero();
+        let mut pdf = 0.0;
+
+        let base = self.base_color.value(uv, p);
+        let metallic = self.metallic.value(uv, p).x;
+        let roughness = self.roughness.value(uv, p).x;
+        let distribution = Ggx::from_roughness(roughness, self.anisotropic.value(uv, p).x);
+
+        // Probability of getting past the clearcoat
+        let mut below = 1.0;
+        let clearcoat = self.clearcoat.value(uv, p).x;
+        if clearcoat > 0.0 {
+            let f0 = vec(0.04, 0.04, 0.04);
+            let coat_prob = clearcoat * schlick_color(f0, wo.z).x;
+            let coat = Ggx::from_roughness(self.clearcoat_roughness.value(uv, p).x, 0.0);
+            if let Some((m, lobe, lobe_pdf)) = eval_reflection(wo, wi, &coat) {
+                value += vec_one() * (clearcoat * schlick_color(f0, wo.dot(&m)).x * lobe);
+                pdf += coat_prob * lobe_pdf;
+            }
+            below = 1.0 - coat_prob;
+        }
+
+        let metal_prob = below * metallic;
+        let glossy = eval_reflection(wo, wi, &distribution);
+        if let Some((m, lobe, lobe_pdf)) = glossy {
+            value += schlick_color(base, wo.dot(&m)) * (metal_prob * lobe);
+            pdf += metal_prob * lobe_pdf;
+        }
+
+        let transmission = self.transmission.value(uv, p).x;
+        let glass_prob = below * (1.0 - metallic) * transmission;
+        if glass_prob > 0.0 {
+            let ior = self.ior.value(uv, p).x;
+            let eta = if hit.front_face { ior } else { 1.0 / ior };
+            let (lobe, lobe_pdf) = eval_dielectric(wo, wi, eta, &distribution);
+            let tint = if wi.z < 0.0 { base } else { vec_one() };
+            value += tint * (glass_prob * lobe);
+            pdf += glass_prob * lobe_pdf;
+        }
+
+        let opaque_prob = below * (1.0 - metallic) * (1.0 - transmission);
+        let tint = tint_color(base);
+        let specular_f0 = lerp(vec_one(), tint, self.specular_tint.value(uv, p).x)
+            * (0.08 * self.specular.value(uv, p).x);
+        let specular_prob = luminance(schlick_color(specular_f0, wo.z)).clamp(0.0, 1.0);
+        if let Some((m, lobe, lobe_pdf)) = glossy {
+            value += schlick_color(specular_f0, wo.dot(&m)) * (opaque_prob * lobe);
+            pdf += opaque_prob * specular_prob * lobe_pdf;
+        }
+
+        if wi.z > 0.0 {
+            let diffuse_prob = opaque_prob * (1.0 - specular_prob);
+            let cos_pdf = wi.z / f32::consts::PI;
+            let h = (wi + wo).normalize();
+            let sheen_color = lerp(vec_one(), tint, self.sheen_tint.value(uv, p).x);
+            let sheen = sheen_color * (self.sheen.value(uv, p).x * (1.0 - wi.dot(&h).clamp(0.0, 1.0)).powi(5));
+            value += (base + f32::consts::PI * sheen) * (diffuse_prob * cos_pdf);
+            pdf += diffuse_prob * cos_pdf;
+        }
+        Some((value, pdf))
+    }
+
+    fn emitted(&self, _ray: &Ray, hit: &HitRecord) -> Vector3<f32> {
+        self.emission.value(hit.uv, hit.p)
+    }
+}
@@ -1,15 +1,44 @@
 use nalgebra::Vector3;
 
-#[derive(Debug)]
+use crate::medium_stack::MediumStack;
+use crate::spectrum::SampledWavelengths;
+
+// What a ray is traced for, objects can be hidden from some of them.
+// Materials set the kind of the rays they scatter from the lobe they sampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RayKind {
+    Camera,
+    Shadow,
+    // Mirror reflection or refraction through smooth glass, a single direction
+    Specular,
+    // Reflection or refraction off a rough microfacet lobe
+    Glossy,
+    // Diffuse lobes and scattering in media
+    Diffuse,
+    // Finds the media around a point, sees every object whatever its visibility
+    Probe,
+}
+
+#[derive(Debug, Clone)]
 pub struct Ray {
     a: Vector3<f32>,
     b: Vector3<f32>,
     pub albedo_normal_ray: bool,
+    // Set in spectral mode, then the color channels are these wavelengths
+    pub wavelengths: Option<SampledWavelengths>,
+    // Dielectrics the ray is inside, None means unchanged from the previous segment
+    pub media: Option<MediumStack>,
+    pub kind: RayKind,
 }
 
 impl Ray {
     pub fn new(a: Vector3<f32>, b: Vector3<f32>) -> Self {
-        return Ray { a, b, albedo_normal_ray: false };
+        return Ray { a, b, albedo_normal_ray: false, wavelengths: None, media: None, kind: RayKind::Camera };
+    }
+
+    pub fn with_kind(mut self, kind: RayKind) -> Self {
+        self.kind = kind;
+        self
     }
 
     pub fn origin(&self) -> Vector3<f32> {
@@ -6,7 +6,7 @@
 
 // pub mod earth_scene;
 // pub mod random_scene;
-// pub mod random_scene_light;
+pub mod random_scene_light;
 // pub mod cornell_box_scene;
 // pub mod cornell_box_vol;
 // pub mod cornell_box_mesh;
@@ -20,9 +20,12 @@ use std::sync::Arc;
 use crate::hittable::Hittable;
 use crate::camera::Camera;
 use crate::material::EnvironmentMaterial;
+use crate::light::Light;
 
 pub struct Scene {
     pub objects:Arc<dyn Hittable>,
     pub environment: Arc<dyn EnvironmentMaterial>,
-    pub camera: Camera
+    pub camera: Camera,
+    // Lights for light sampling, area lights also have to be in objects
+    pub lights: Vec<Arc<dyn Light>>,
 }
\ No newline at end of file
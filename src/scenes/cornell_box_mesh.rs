@@ -17,10 +17,7 @@ use crate::scenes::prefabs::cornell_box::{cornell_box, cornell_box_camera, corne
 pub fn cornell_box_mesh() -> Scene {
 
     let aluminium  = Arc::new(Metal { albedo: Arc::new(ConstantTex { color: vec3(0.8, 0.85, 0.85) } ), fuzz: 0.25});
-    let glass = Arc::new(Dielectric {
-        color: vec(1.0, 1.0, 1.0),
-        ..Dielectric::default()
-    });
+    let glass = Arc::new(Dielectric::default());
 
 
     let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
@@ -46,6 +43,7 @@ pub fn cornell_box_mesh() -> Scene {
     Scene {
         camera: cornell_box_camera(),
         objects: BVHNode::build(objects, 0),
-        environment: cornell_box_environment()
+        environment: cornell_box_environment(),
+        lights: Vec::new(),
     }
 }
\ No newline at end of file
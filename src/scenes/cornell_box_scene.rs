@@ -16,7 +16,7 @@ pub fn cornell_box(aspect: f32) -> Scene {
     let red = Arc::new(Lambertian { albedo: Arc::new(ConstantTex { color: vec3(0.65, 0.05, 0.05) })});
     let white = Arc::new(Lambertian { albedo: Arc::new(ConstantTex { color: vec3(0.73, 0.73, 0.73) })});
     let green = Arc::new(Lambertian { albedo: Arc::new(ConstantTex { color: vec3(0.12, 0.45, 0.15) })});
-    let light = Arc::new(DiffuseLight { emit: Arc::new(ConstantTex { color: vec3(14.0, 14.0, 14.0) })});
+    let light = Arc::new(DiffuseLight { emit: Arc::new(ConstantTex { color: vec3(14.0, 14.0, 14.0) }), two_sided: false });
     let aluminium  = Arc::new(Metal { albedo: Arc::new(ConstantTex { color: vec3(0.8, 0.85, 0.85) } ), fuzz: 0.0});
 
     let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
@@ -42,13 +42,13 @@ pub fn cornell_box(aspect: f32) -> Scene {
         material: white.clone(),
         rect_type: XZ
     }));
-    objects.push(Arc::new(FlipFace::new(AARect { 
+    objects.push(Arc::new(AARect { 
         xy0: vec2(213.0, 227.0), 
         xy1: vec2(343.0, 332.0),
         k: 554.0,
         material: light.clone(),
         rect_type: XZ
-    })));
+    }));
     // objects.push(Arc::new(FlipFace::new(AARect { 
     //     xy0: vec2(113.0, 127.0), 
     //     xy1: vec2(443.0, 442.0),
@@ -111,6 +111,7 @@ pub fn cornell_box(aspect: f32) -> Scene {
     Scene {
         camera: Camera::new(lookfrom, lookat, vup, vfov, aspect, aperture, dist_to_focus),
         objects: BVHNode::build(objects, 0),
-        environment: Arc::new(Environment { emit: Arc::new(ConstantTex { color: vec_zero() })})
+        environment: Arc::new(Environment { emit: Arc::new(ConstantTex { color: vec_zero() })}),
+        lights: Vec::new(),
     }
 }
\ No newline at end of file
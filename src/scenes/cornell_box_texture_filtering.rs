@@ -5,8 +5,9 @@ use std::{fs, io};
 
 use crate::aarect::{AARect, AARectType::*};
 use crate::bvh::BVHNode;
-use crate::hittable::{FlipFace, Hittable, Transform};
-use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal};
+use crate::hittable::{Hittable, Transform};
+use crate::light::{AreaLight, Light, TransformedShape};
+use crate::material::{Dielectric, Lambertian, Metal};
 use crate::mesh::Mesh;
 use crate::scenes::prefabs::cornell_box::{
     cornell_box, cornell_box_camera, cornell_box_environment,
@@ -20,10 +21,7 @@ pub fn scene() -> Scene {
     let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
 
     objects.push(cornell_box());
-    let glass = Arc::new(Dielectric {
-        color: vec(1.0, 1.0, 1.0),
-        ..Dielectric::default()
-    });
+    let glass = Arc::new(Dielectric::default());
 
     let earth_image = image::open("assets/topo.jpg").unwrap().to_rgb();
     // let decoder = image::hdr::HdrDecoder::new(io::BufReader::new(
@@ -44,35 +42,21 @@ pub fn scene() -> Scene {
                 .wrap_mode(Clamp),
         ),
     });
-    let small_light = Arc::new(DiffuseLight {
-        emit: Arc::new(ConstantTex {
-            color: vec3(6.0, 6.0, 6.0),
-        }),
-    });
-
-    objects.push(Arc::new(Transform::new_b(
-        Arc::new(FlipFace::new(AARect {
+    // Two small lights facing back into the box
+    let mut lights: Vec<Arc<dyn Light>> = Vec::new();
+    for (offset, rotation) in [(vec3(-200.0, 50.0, 500.0), vec3(20.0, -20.0, 0.0)), (vec3(200.0, 500.0, 500.0), vec3(-20.0, 20.0, 0.0))] {
+        let rect = AARect {
             xy0: vec2(-50.0, -50.0),
             xy1: vec2(50.0, 50.0),
             k: 0.0,
-            material: small_light.clone(),
+            material: Arc::new(Lambertian { albedo: ConstantTex::new_arc(vec_zero()) }),
             rect_type: XY,
-        })),
-        vec3(-200.0, 50.0, 500.0),
-        vec3(20.0, -20.0, 0.0),
-    )));
-
-    objects.push(Arc::new(Transform::new_b(
-        Arc::new(FlipFace::new(AARect {
-            xy0: vec2(-50.0, -50.0),
-            xy1: vec2(50.0, 50.0),
-            k: 0.0,
-            material: small_light.clone(),
-            rect_type: XY,
-        })),
-        vec3(200.0, 500.0, 500.0),
-        vec3(-20.0, 20.0, 0.0),
-    )));
+        };
+        let emission = ConstantTex::new_arc(vec3(6.0, 6.0, 6.0));
+        let light = Arc::new(AreaLight::new(TransformedShape::new(rect, offset, rotation), emission).flipped(true));
+        objects.push(light.object());
+        lights.push(light);
+    }
 
     objects.push(Arc::new(Transform::new(
         Sphere::new(vec_zero(), 199.999, earth_material),
@@ -90,5 +74,6 @@ pub fn scene() -> Scene {
         camera: cornell_box_camera(),
         objects: BVHNode::build(objects, 0),
         environment: cornell_box_environment(),
+        lights,
     }
 }
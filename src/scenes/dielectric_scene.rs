@@ -7,6 +7,7 @@ use crate::bvh::BVHNode;
 use crate::camera::Camera;
 use crate::hittable::{Hittable, HittableList};
 use crate::material::{Dielectric, Lambertian, Metal};
+use crate::volume::HomogeneousMedium;
 use crate::sphere::Sphere;
 use crate::texture::{CheckerTex, CheckerTexMap, ConstantTex};
 use crate::vec::{vec, vec_zero};
@@ -49,33 +50,28 @@ pub fn dielectric_scene(aspect: f32) -> (Camera,Arc<dyn Hittable>) {
     //     scale: 0.5,
     // }});
 
-    // Sphere 1
+    // Sphere 1, hollow. Both walls share the material so they bound one medium.
+    let shell: Arc<Dielectric> = Arc::new(Dielectric {
+        medium: Some(Arc::new(HomogeneousMedium::absorbing(vec(1.0, 1.0, 0.1)))),
+        ..Dielectric::default()
+    });
     world.push(Sphere {
         center: Vector3::new(-1.0, 0.0, -1.0),
         radius: 0.5,
-        material: Arc::new(Dielectric {
-            color: vec(0.1, 0.1, 1.0),
-            density: 0.1,
-            ..Dielectric::default()
-        }),
+        material: shell.clone(),
     });
 
     world.push(Sphere {
         center: Vector3::new(-1.0, 0.0, -1.0),
         radius: -0.45,
-        material: Arc::new(Dielectric {
-            color: vec(0.1, 0.1, 1.0),
-            density: 0.1,
-            ..Dielectric::default()
-        }),
+        material: shell,
     });
 
     world.push(Sphere {
         center: Vector3::new(-1.0, 0.0, -1.0),
         radius: 0.2,
         material: Arc::new(Dielectric {
-            color: vec(0.1, 0.1, 1.0),
-            density: 0.1,
+            medium: Some(Arc::new(HomogeneousMedium::absorbing(vec(1.0, 1.0, 0.1)))),
             ..Dielectric::default()
         }),
     });
@@ -102,10 +98,7 @@ pub fn dielectric_scene(aspect: f32) -> (Camera,Arc<dyn Hittable>) {
     world.push(Sphere {
         center: Vector3::new(1.0, 0.0, -1.0),
         radius: 0.5,
-        material: Arc::new(Dielectric {
-            color: vec(1.0, 1.0, 1.0),
-            ..Dielectric::default()
-        }),
+        material: Arc::new(Dielectric::default()),
     });
 
 
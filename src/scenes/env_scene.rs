@@ -21,10 +21,7 @@ use crate::camera::{Camera, ApertureShape};
 pub fn scene() -> Scene {
     let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
 
-    let glass = Arc::new(Dielectric {
-        color: vec(1.0, 1.0, 1.0),
-        ..Dielectric::default()
-    });
+    let glass = Arc::new(Dielectric::default());
     let aluminium  = Arc::new(Metal { albedo: Arc::new(ConstantTex { color: vec3(0.8, 0.85, 0.85) } ), fuzz: 0.0});
 
 
@@ -40,13 +37,6 @@ pub fn scene() -> Scene {
         decoder.read_image_hdr().unwrap().iter().flat_map(|p| vec![p[0], p[1], p[2]]).collect::<Vec<f32>>()
     ).unwrap();
 
-    let earth_material = Arc::new(Lambertian {
-        albedo: Arc::new(
-            ImageTexture::new(earth_image.clone())
-                .sampler(Bilinear)
-                .wrap_mode(Clamp),
-        ),
-    });
     let earth_material_new = Arc::new(DielectricSurfaceLambert{
         albedo: Arc::new(
             ImageTexture::new(earth_image.clone())
@@ -79,20 +69,6 @@ pub fn scene() -> Scene {
         vec_zero(),
     )));
 
-    // This doesn't work properly becuase de dieletric assumes and interface
-    // with a medium with ref_idx ~1 (like air)
-    // objects.push(Arc::new(Transform::new(
-    //     Sphere::new(vec_zero(), 199.999, earth_material),
-    //     vec3(0.0, 200.0, -100.0),
-    //     vec_zero(),
-    // )));
-    // objects.push(Arc::new(Transform::new(
-    //     Sphere::new(vec_zero(), 200.0, glass.clone()),
-    //     vec3(0.0, 200.0, -100.0),
-    //     vec_zero(),
-    // )));
-
-
     objects.push(Arc::new(Transform::new(
         Sphere::new(vec_zero(), 200.0, earth_material_new.clone()),
         vec3(0.0, 200.0, -100.0),
@@ -120,5 +96,6 @@ pub fn scene() -> Scene {
         camera,
         objects: BVHNode::build(objects, 0),
         environment: env_material,
+        lights: Vec::new(),
     }
 }
@@ -13,7 +13,7 @@ pub fn cornell_box() ->Arc<dyn Hittable> {
     let red = Arc::new(Lambertian { albedo: Arc::new(ConstantTex { color: vec3(0.65, 0.05, 0.05) })});
     let white = Arc::new(Lambertian { albedo: Arc::new(ConstantTex { color: vec3(0.73, 0.73, 0.73) })});
     let green = Arc::new(Lambertian { albedo: Arc::new(ConstantTex { color: vec3(0.12, 0.45, 0.15) })});    
-    let light = Arc::new(DiffuseLight { emit: Arc::new(ConstantTex { color: vec3(7.0, 7.0, 7.0) })});
+    let light = Arc::new(DiffuseLight { emit: Arc::new(ConstantTex { color: vec3(7.0, 7.0, 7.0) }), two_sided: false });
 
     let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
 
@@ -38,13 +38,14 @@ pub fn cornell_box() ->Arc<dyn Hittable> {
         material: white.clone(),
         rect_type: XZ
     }));
-    objects.push(Arc::new(FlipFace::new(AARect { 
+    // XZ rects face down
+    objects.push(Arc::new(AARect { 
         xy0: vec2(-113.0, -113.0), 
         xy1: vec2(113.0, 113.0),
         k: 554.0,
         material: light.clone(),
         rect_type: XZ
-    })));
+    }));
     objects.push(Arc::new(AARect { 
         xy0: vec2(-278.0, -278.0), 
         xy1: vec2(278.0, 278.0),
@@ -6,6 +6,7 @@ use std::sync::Arc;
 use crate::hittable::{HittableList, Hittable};
 use crate::camera::Camera;
 use crate::material::{Dielectric, Lambertian, Metal, SimpleEnvironment};
+use crate::volume::HomogeneousMedium;
 use crate::sphere::Sphere;
 use crate::vec::{vec, random_vec, random_vec_range, vec_zero};
 use crate::bvh::BVHNode;
@@ -61,8 +62,7 @@ pub fn random_scene(aspect: f32) -> Scene {
                     radius: 0.2, 
                     material: Arc::new(Dielectric { 
                         ref_idx: 1.5, 
-                        color,
-                        density: 0.1,
+                        medium: Some(Arc::new(HomogeneousMedium::absorbing(0.1 * color.map(|c| 1.0 / c)))),
                         ..Dielectric::default() }),
                     }
                 ))
@@ -75,8 +75,7 @@ pub fn random_scene(aspect: f32) -> Scene {
         radius: 1.0,
         material: Arc::new(Dielectric {
             ref_idx: 1.5, 
-            color: vec(1.0, 1.0, 1.0),
-            density: 0.1,
+            medium: Some(Arc::new(HomogeneousMedium::absorbing(vec(0.1, 0.1, 0.1)))),
             ..Dielectric::default()
         })
     }));
@@ -114,6 +113,7 @@ pub fn random_scene(aspect: f32) -> Scene {
     Scene {
         camera: Camera::new(lookfrom, lookat, vup, 20.0, aspect, aperture, dist_to_focus),
         objects: BVHNode::build(objects, 0),
-        environment: Arc::new(SimpleEnvironment {})
+        environment: Arc::new(SimpleEnvironment {}),
+        lights: Vec::new(),
     }
 }
\ No newline at end of file
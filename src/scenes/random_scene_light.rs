@@ -5,18 +5,29 @@ use std::sync::Arc;
 
 use crate::hittable::{HittableList, Hittable};
 use crate::camera::Camera;
-use crate::material::{Dielectric, Lambertian, Metal, Environment, DiffuseLight};
+use crate::material::{Dielectric, Lambertian, Metal, Environment};
+use crate::volume::HomogeneousMedium;
 use crate::sphere::Sphere;
 use crate::aarect::{AARect, AARectType::*};
 use crate::vec::{vec, vec2, vec3, random_vec, random_vec_range, vec_zero};
 use crate::bvh::BVHNode;
 use crate::texture::{ConstantTex, CheckerTex, ImageTexture};
 use crate::scenes::Scene;
+use crate::light::{AreaLight, Light, Shape};
+
+// Emissive surface that is also sampled as a light, the shape's own material
+// is replaced by the emission
+fn area_light(shape: impl Shape + 'static, color: Vector3<f32>, two_sided: bool, objects: &mut Vec<Arc<dyn Hittable>>, lights: &mut Vec<Arc<dyn Light>>) {
+    let light = Arc::new(AreaLight::new(shape, Arc::new(ConstantTex { color })).two_sided(two_sided));
+    objects.push(light.object());
+    lights.push(light);
+}
 
 pub fn random_scene_light(aspect: f32) -> Scene {
     let mut rng = thread_rng();
 
     let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
+    let mut lights: Vec<Arc<dyn Light>> = Vec::new();
 
     let checker_tex = Arc::new(CheckerTex {
         odd: Arc::new(ConstantTex { color: vec(0.2, 0.3, 0.1)}),
@@ -37,7 +48,14 @@ pub fn random_scene_light(aspect: f32) -> Scene {
         for b in -11..11 {
             let choose_mat = rng.gen::<f32>();
             let center = Vector3::new(a as f32 + 0.9 * rng.gen::<f32>(), 0.2, b as f32 + 0.9 * rng.gen::<f32>());
-            if choose_mat < 0.8 {
+            if choose_mat < 0.1 {
+                // light
+                let hsl_color = HSL {h: rng.gen_range(0.0, 360.0), s: 1.0, l: 0.6};
+                let rgb_color = hsl_color.to_rgb();
+                let color = Vector3::new(rgb_color.0 as f32 / 255.0 , rgb_color.1 as f32 / 255.0 , rgb_color.2 as f32 / 255.0 );
+                let sphere = Sphere { center, radius: 0.2, material: Arc::new(Lambertian { albedo: Arc::new(ConstantTex { color }) }) };
+                area_light(sphere, 4.0 * color, false, &mut objects, &mut lights);
+            } else if choose_mat < 0.8 {
                 // diffuse
                 let albedo = Arc::new(ConstantTex {color: random_vec().component_mul(&random_vec())});
                 objects.push(Arc::new(Sphere{
@@ -62,8 +80,7 @@ pub fn random_scene_light(aspect: f32) -> Scene {
                     radius: 0.2, 
                     material: Arc::new(Dielectric { 
                         ref_idx: 1.5, 
-                        color,
-                        density: 0.1,
+                        medium: Some(Arc::new(HomogeneousMedium::absorbing(0.1 * color.map(|c| 1.0 / c)))),
                         ..Dielectric::default() }),
                     }
                 ))
@@ -76,8 +93,7 @@ pub fn random_scene_light(aspect: f32) -> Scene {
         radius: 1.0,
         material: Arc::new(Dielectric {
             ref_idx: 1.5, 
-            color: vec(1.0, 1.0, 1.0),
-            density: 0.1,
+            medium: Some(Arc::new(HomogeneousMedium::absorbing(vec(0.1, 0.1, 0.1)))),
             ..Dielectric::default()
         })
     }));
@@ -88,34 +104,28 @@ pub fn random_scene_light(aspect: f32) -> Scene {
             albedo: Arc::new(ConstantTex {color: vec(0.4, 0.2, 0.1)})
         })
     }));
-    objects.push(Arc::new(Sphere {
+    let sphere = Sphere {
         center: vec(2.0, 3.0, -1.0),
         radius: 1.0,
-        material: Arc::new(DiffuseLight { 
-            emit: Arc::new(ConstantTex {
-                color: vec(4.0, 4.0, 4.0)
-            })
-        })
-    }));
+        material: Arc::new(Lambertian { albedo: Arc::new(ConstantTex { color: vec(1.0, 1.0, 1.0) }) }),
+    };
+    area_light(sphere, vec(4.0, 4.0, 4.0), false, &mut objects, &mut lights);
     objects.push(Arc::new(Sphere {
         center: Vector3::new(2.5, 0.75, 3.0),
         radius: 0.75,
         material: Arc::new(Lambertian {
-            albedo: Arc::new(ImageTexture::new(String::from("assets/earthmap.jpg"))),
+            albedo: Arc::new(ImageTexture::new(image::open("assets/earthmap.jpg").unwrap().to_rgb())),
         }),
     }));
 
-    objects.push(Arc::new(AARect {
+    let rect = AARect {
         xy0: vec2(3.0, 1.0),
         xy1: vec2(5.0, 3.0),
         k: -2.0,
-        material: Arc::new(DiffuseLight { 
-            emit: Arc::new(ConstantTex {
-                color: vec(4.0, 4.0, 4.0)
-            })
-        }),
+        material: Arc::new(Lambertian { albedo: Arc::new(ConstantTex { color: vec(1.0, 1.0, 1.0) }) }),
         rect_type: XY
-    }));
+    };
+    area_light(rect, vec(4.0, 4.0, 4.0), true, &mut objects, &mut lights);
 
 
     let lookfrom = vec(12.0, 2.0, 3.0);
@@ -128,6 +138,7 @@ pub fn random_scene_light(aspect: f32) -> Scene {
     Scene {
         camera: Camera::new(lookfrom, lookat, vup, 20.0, aspect, aperture, dist_to_focus),
         objects: BVHNode::build(objects, 0),
-        environment: Arc::new(Environment { emit: Arc::new(ConstantTex { color: vec(0.1, 0.05, 0.05)})})
+        environment: Arc::new(Environment { emit: Arc::new(ConstantTex { color: vec(0.1, 0.05, 0.05)})}),
+        lights,
     }
 }
\ No newline at end of file
@@ -0,0 +1,135 @@
+use nalgebra::Vector3;
+use std::f32;
+
+use crate::light::{DirectionalLight, ALL_LIGHT_GROUPS};
+use crate::material::EnvironmentMaterial;
+use crate::ray::Ray;
+use crate::spectrum;
+use crate::vec::{luminance, vec3};
+
+// Daylight sky (Preetham et al. 1999, "A Practical Analytic Model for
+// Daylight"). The sky is a Perez distribution of luminance and chromaticity
+// around the zenith values, fitted to turbidity and sun position. The sun
+// direct light is a disk of extraterrestrial sunlight attenuated by Rayleigh
+// and aerosol scattering along the air mass it passes through.
+//
+// Radiance is in kcd/m^2 times scale, a clear sky is around 5 to 15 at the
+// zenith. The sun is about 100000 times brighter than that, so paths that only
+// find it by chance are noisy. Add sun_light() to the scene lights and turn
+// off sun_disk so it is sampled instead and not counted twice. Below the
+// horizon the ground reflects the horizon.
+pub struct PreethamSky {
+    // Towards the sun, y is up
+    pub sun_direction: Vector3<f32>,
+    // 2 is a very clear sky, 10 a hazy one
+    pub turbidity: f32,
+    // Half angle of the sun disk in degrees
+    pub sun_angular_radius: f32,
+    // Luminance of the sun outside of the atmosphere, 0 hides the disk
+    pub sun_luminance: f32,
+    // Whether camera and scattered rays see the sun
+    pub sun_disk: bool,
+    pub scale: f32,
+    pub ground_albedo: Vector3<f32>,
+}
+
+impl Default for PreethamSky {
+    fn default() -> Self {
+        PreethamSky {
+            sun_direction: vec3(0.0, 1.0, 1.0).normalize(),
+            turbidity: 3.0,
+            sun_angular_radius: 0.2665,
+            sun_luminance: 1.96e6,
+            sun_disk: true,
+            scale: 1.0,
+            ground_albedo: vec3(0.3, 0.3, 0.3),
+        }
+    }
+}
+
+// Perez coefficients A to E as linear functions of turbidity
+const PEREZ_LUMINANCE: [[f32; 2]; 5] = [[0.1787, -1.4630], [-0.3554, 0.4275], [-0.0227, 5.3251], [0.1206, -2.5771], [-0.0670, 0.3703]];
+const PEREZ_X: [[f32; 2]; 5] = [[-0.0193, -0.2592], [-0.0665, 0.0008], [-0.0004, 0.2125], [-0.0641, -0.8989], [-0.0033, 0.0452]];
+const PEREZ_Y: [[f32; 2]; 5] = [[-0.0167, -0.2608], [-0.0950, 0.0092], [-0.0079, 0.2102], [-0.0441, -1.6537], [-0.0109, 0.0529]];
+
+// Relative brightness at zenith angle theta and angle gamma from the sun
+fn perez(coefficients: &[[f32; 2]; 5], turbidity: f32, theta: f32, gamma: f32) -> f32 {
+    let [a, b, c, d, e] = coefficients.map(|[slope, offset]| slope * turbidity + offset);
+    let cos_gamma = gamma.cos();
+    (1.0 + a * (b / theta.cos().max(0.01)).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+// Cubic in the sun zenith angle for each power of turbidity
+fn zenith_chromaticity(coefficients: [[f32; 4]; 3], turbidity: f32, theta_sun: f32) -> f32 {
+    let powers = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+    let terms = coefficients.map(|c| c.iter().zip(powers.iter()).map(|(c, p)| c * p).sum::<f32>());
+    turbidity * turbidity * terms[0] + turbidity * terms[1] + terms[2]
+}
+
+impl PreethamSky {
+    fn sky(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let sun = self.sun_direction.normalize();
+        let t = self.turbidity;
+        // The fit is only valid with the sun above the horizon
+        let theta_sun = sun.y.clamp(0.0, 1.0).acos().min(f32::consts::FRAC_PI_2 - 0.01);
+        let theta = direction.y.clamp(0.0, 1.0).acos();
+        let gamma = direction.dot(&sun).clamp(-1.0, 1.0).acos();
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (f32::consts::PI - 2.0 * theta_sun);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let zenith_x = zenith_chromaticity(
+            [[0.00166, -0.00375, 0.00209, 0.0], [-0.02903, 0.06377, -0.03202, 0.00394], [0.11693, -0.21196, 0.06052, 0.25886]],
+            t, theta_sun);
+        let zenith_y = zenith_chromaticity(
+            [[0.00275, -0.00610, 0.00317, 0.0], [-0.04214, 0.08970, -0.04153, 0.00516], [0.15346, -0.26756, 0.06670, 0.26688]],
+            t, theta_sun);
+
+        let relative = |coefficients| perez(coefficients, t, theta, gamma) / perez(coefficients, t, 0.0, theta_sun);
+        let luminance = zenith_luminance * relative(&PEREZ_LUMINANCE);
+        let x = zenith_x * relative(&PEREZ_X);
+        let y = zenith_y * relative(&PEREZ_Y);
+        let xyz = vec3(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        (spectrum::xyz_to_linear_srgb() * xyz).map(|c| c.max(0.0))
+    }
+
+    // Sunlight after Rayleigh and aerosol extinction along the air mass
+    // (Kasten and Young 1989), evaluated at a red, green and blue wavelength
+    fn sun(&self) -> Vector3<f32> {
+        let sun = self.sun_direction.normalize();
+        let zenith_degrees = sun.y.clamp(0.0, 1.0).acos().to_degrees();
+        let air_mass = 1.0 / (sun.y.max(0.0) + 0.50572 * (96.07995 - zenith_degrees).powf(-1.6364));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = vec3(0.68, 0.55, 0.45).map(|lambda: f32| {
+            let rayleigh = 0.008735 * lambda.powf(-4.08);
+            let aerosol = beta * lambda.powf(-1.3);
+            (-(rayleigh + aerosol) * air_mass).exp()
+        });
+        let color = spectrum::blackbody(5778.0);
+        (color / luminance(color)).component_mul(&transmittance) * self.sun_luminance
+    }
+
+    // The sun as a light with the irradiance of the disk, in scene units
+    pub fn sun_light(&self) -> DirectionalLight {
+        let sun = self.sun_direction.normalize();
+        let cos_max = self.sun_angular_radius.to_radians().cos();
+        let solid_angle = 2.0 * f32::consts::PI * (1.0 - cos_max);
+        let irradiance = if sun.y > 0.0 { self.sun() * (solid_angle * self.scale) } else { vec3(0.0, 0.0, 0.0) };
+        DirectionalLight { direction: sun, irradiance, angular_radius: self.sun_angular_radius, light_groups: ALL_LIGHT_GROUPS }
+    }
+}
+
+impl EnvironmentMaterial for PreethamSky {
+    fn emit(&self, ray: &Ray) -> Vector3<f32> {
+        let direction = ray.direction().normalize();
+        if direction.y < 0.0 {
+            let horizon = vec3(direction.x, 0.0, direction.z).try_normalize(1e-6).unwrap_or_else(|| vec3(1.0, 0.0, 0.0));
+            return self.sky(horizon).component_mul(&self.ground_albedo) * self.scale;
+        }
+        let mut radiance = self.sky(direction);
+        let sun = self.sun_direction.normalize();
+        if self.sun_disk && self.sun_luminance > 0.0 && sun.y > 0.0 && direction.dot(&sun) >= self.sun_angular_radius.to_radians().cos() {
+            radiance += self.sun();
+        }
+        radiance * self.scale
+    }
+}
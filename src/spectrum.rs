@@ -0,0 +1,261 @@
+use nalgebra::{Matrix3, Vector3};
+use std::sync::OnceLock;
+
+use crate::vec::vec3;
+
+// Spectral rendering. A path carries three wavelengths instead of RGB, one
+// hero wavelength and two others rotated by a third of the visible range
+// (Wilkie et al. 2014, "Hero Wavelength Spectral Sampling"), stored in the
+// channels of the usual Vector3 so materials don't have to know about it.
+
+pub const LAMBDA_MIN: f32 = 380.0;
+pub const LAMBDA_MAX: f32 = 720.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SampledWavelengths {
+    // In nm, the hero wavelength is x
+    pub lambda: Vector3<f32>,
+    // Set once a wavelength dependent event (dispersion) has dropped the
+    // secondary wavelengths
+    pub hero_only: bool,
+}
+
+impl SampledWavelengths {
+    pub fn sample(u: f32) -> Self {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let hero = u * range;
+        let rotate = |i: f32| LAMBDA_MIN + (hero + i * range / 3.0) % range;
+        SampledWavelengths {
+            lambda: vec3(rotate(0.0), rotate(1.0), rotate(2.0)),
+            hero_only: false,
+        }
+    }
+
+    pub fn hero(&self) -> f32 {
+        self.lambda.x
+    }
+
+    pub fn terminate_secondary(&self) -> Self {
+        SampledWavelengths { hero_only: true, ..*self }
+    }
+}
+
+// RGB to spectrum conversion (Smits 1999, "An RGB-to-Spectrum Conversion for
+// Reflectances"), ten bins over the visible range
+const SMITS_WHITE: [f32; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f32; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f32; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f32; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f32; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f32; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f32; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+pub fn rgb_to_spectrum(rgb: Vector3<f32>, lambda: f32) -> f32 {
+    let bin = (((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN) * 10.0) as usize).min(9);
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    let s = |table: [f32; 10]| table[bin];
+
+    let value = if r <= g && r <= b {
+        r * s(SMITS_WHITE) + if g <= b {
+            (g - r) * s(SMITS_CYAN) + (b - g) * s(SMITS_BLUE)
+        } else {
+            (b - r) * s(SMITS_CYAN) + (g - b) * s(SMITS_GREEN)
+        }
+    } else if g <= r && g <= b {
+        g * s(SMITS_WHITE) + if r <= b {
+            (r - g) * s(SMITS_MAGENTA) + (b - r) * s(SMITS_BLUE)
+        } else {
+            (b - g) * s(SMITS_MAGENTA) + (r - b) * s(SMITS_RED)
+        }
+    } else {
+        b * s(SMITS_WHITE) + if r <= g {
+            (r - b) * s(SMITS_YELLOW) + (g - r) * s(SMITS_GREEN)
+        } else {
+            (g - b) * s(SMITS_YELLOW) + (r - g) * s(SMITS_RED)
+        }
+    };
+    value.max(0.0)
+}
+
+// An RGB color evaluated at the sampled wavelengths
+pub fn upsample(rgb: Vector3<f32>, wavelengths: &SampledWavelengths) -> Vector3<f32> {
+    wavelengths.lambda.map(|lambda| rgb_to_spectrum(rgb, lambda))
+}
+
+// Analytic fit of the CIE 1931 color matching functions (Wyman et al. 2013,
+// "Simple Analytic Approximations to the CIE XYZ Color Matching Functions")
+fn piecewise_gaussian(x: f32, mu: f32, sigma_low: f32, sigma_high: f32) -> f32 {
+    let t = (x - mu) / if x < mu { sigma_low } else { sigma_high };
+    (-0.5 * t * t).exp()
+}
+
+pub fn cie_xyz(lambda: f32) -> Vector3<f32> {
+    let g = |mu, s1, s2| piecewise_gaussian(lambda, mu, s1, s2);
+    vec3(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+pub fn xyz_to_linear_srgb() -> Matrix3<f32> {
+    Matrix3::new(
+        3.2404542, -1.5371385, -0.4985314,
+        -0.969266, 1.8760108, 0.0415560,
+        0.0556434, -0.2040259, 1.0572252,
+    )
+}
+
+// Linear sRGB of a spectrum that is 1 everywhere, used to white balance the
+// film so a white RGB reflector still renders white
+fn white_point() -> Vector3<f32> {
+    static WHITE: OnceLock<Vector3<f32>> = OnceLock::new();
+    *WHITE.get_or_init(|| {
+        let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+        let xyz = (0..steps).fold(Vector3::zeros(), |acc, i| acc + cie_xyz(LAMBDA_MIN + i as f32 + 0.5));
+        xyz_to_linear_srgb() * xyz
+    })
+}
+
+// Film response of one spectral sample. The wavelengths are uniformly
+// distributed so the Monte Carlo estimate is the average over the three
+// wavelengths times the range, which the white balance divides out again.
+pub fn to_rgb(radiance: Vector3<f32>, wavelengths: &SampledWavelengths) -> Vector3<f32> {
+    let mut xyz = Vector3::zeros();
+    for i in 0..3 {
+        xyz += radiance[i] * cie_xyz(wavelengths.lambda[i]);
+    }
+    let xyz = xyz * (LAMBDA_MAX - LAMBDA_MIN) / 3.0;
+    (xyz_to_linear_srgb() * xyz).component_div(&white_point())
+}
+
+// Blackbody emission for fire and hot gas. Brightness follows Planck's law,
+// scaled so a body at BLACKBODY_REFERENCE Kelvin (a typical flame) has a
+// luminance of one. It rises steeply: 1000 K is about 3000 times darker and
+// 2000 K about 60 times brighter than the reference.
+pub const BLACKBODY_REFERENCE: f32 = 1500.0;
+// Colder bodies don't glow visibly, hotter ones are clamped
+const BLACKBODY_MIN: f32 = 300.0;
+const BLACKBODY_MAX: f32 = 20000.0;
+const BLACKBODY_STEP: f32 = 50.0;
+
+// Spectral radiance in W / (sr m^3), computed in f64 as it spans many orders
+// of magnitude
+fn planck(lambda: f32, kelvin: f32) -> f64 {
+    const H: f64 = 6.626_070_15e-34;
+    const C: f64 = 299_792_458.0;
+    const K_B: f64 = 1.380_649e-23;
+    let l = lambda as f64 * 1e-9;
+    2.0 * H * C * C / (l.powi(5) * (H * C / (l * K_B * kelvin as f64)).exp_m1())
+}
+
+// Film color (unit luminance) and log luminance of a blackbody every
+// BLACKBODY_STEP Kelvin, white balanced like to_rgb so RGB and spectral
+// renders agree
+fn blackbody_table() -> &'static [(Vector3<f32>, f64)] {
+    static TABLE: OnceLock<Vec<(Vector3<f32>, f64)>> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let steps = ((BLACKBODY_MAX - BLACKBODY_MIN) / BLACKBODY_STEP) as usize + 1;
+        (0..steps).map(|i| {
+            let kelvin = BLACKBODY_MIN + i as f32 * BLACKBODY_STEP;
+            let mut xyz = [0.0f64; 3];
+            let mut lambda = LAMBDA_MIN + 0.5;
+            while lambda < LAMBDA_MAX {
+                let radiance = planck(lambda, kelvin);
+                let cmf = cie_xyz(lambda);
+                for c in 0..3 {
+                    xyz[c] += radiance * cmf[c] as f64;
+                }
+                lambda += 1.0;
+            }
+            let scale = xyz[1];
+            let rgb = (xyz_to_linear_srgb() * vec3((xyz[0] / scale) as f32, 1.0, (xyz[2] / scale) as f32))
+                .component_div(&white_point());
+            let luminance = crate::vec::luminance(rgb);
+            (rgb / luminance, scale.ln() + (luminance as f64).ln())
+        }).collect()
+    })
+}
+
+// Color and log luminance relative to the reference, None below BLACKBODY_MIN
+fn blackbody_lookup(kelvin: f32) -> Option<(Vector3<f32>, f64)> {
+    if kelvin.is_nan() || kelvin < BLACKBODY_MIN {
+        return None;
+    }
+    let table = blackbody_table();
+    let x = (kelvin.min(BLACKBODY_MAX) - BLACKBODY_MIN) / BLACKBODY_STEP;
+    let i = (x as usize).min(table.len() - 2);
+    let f = x - i as f32;
+    let color = table[i].0 * (1.0 - f) + table[i + 1].0 * f;
+    let log_luminance = table[i].1 * (1.0 - f as f64) + table[i + 1].1 * f as f64;
+    Some((color, log_luminance - blackbody_reference()))
+}
+
+fn blackbody_reference() -> f64 {
+    blackbody_table()[((BLACKBODY_REFERENCE - BLACKBODY_MIN) / BLACKBODY_STEP) as usize].1
+}
+
+// Linear sRGB radiance of a blackbody
+pub fn blackbody(kelvin: f32) -> Vector3<f32> {
+    match blackbody_lookup(kelvin) {
+        Some((color, log_luminance)) => color * log_luminance.exp() as f32,
+        None => Vector3::zeros(),
+    }
+}
+
+// Radiance of a blackbody at the sampled wavelengths, with the same scale as
+// `blackbody`
+pub fn blackbody_spectrum(kelvin: f32, wavelengths: &SampledWavelengths) -> Vector3<f32> {
+    if kelvin.is_nan() || kelvin < BLACKBODY_MIN {
+        return Vector3::zeros();
+    }
+    let reference = blackbody_reference();
+    // The table integrates with unit steps while to_rgb scales by the range
+    // and divides by the white point, which is also a sum with unit steps
+    wavelengths.lambda.map(|lambda| (planck(lambda, kelvin.min(BLACKBODY_MAX)).ln() - reference).exp() as f32)
+}
+
+// Wavelength dependent index of refraction, wavelengths in nm
+#[derive(Clone, Copy, Debug)]
+pub enum Dispersion {
+    // n = a + b / lambda^2, lambda in um
+    Cauchy { a: f32, b: f32 },
+    // n^2 = 1 + sum b_i lambda^2 / (lambda^2 - c_i), lambda in um
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+    pub fn ior(&self, lambda: f32) -> f32 {
+        let l2 = (lambda / 1000.0).powi(2);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let n2 = 1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>();
+                n2.max(1.0).sqrt()
+            }
+        }
+    }
+
+    // Schott N-BK7 crown glass
+    pub fn bk7() -> Self {
+        Dispersion::Sellmeier {
+            b: [1.039_612, 0.231_792_34, 1.010_469_4],
+            c: [0.006_000_699, 0.020_017_914, 103.560_65],
+        }
+    }
+
+    // Schott SF11 dense flint glass, strong dispersion
+    pub fn flint() -> Self {
+        Dispersion::Sellmeier {
+            b: [1.737_597, 0.313_747_35, 1.898_781],
+            c: [0.013_188_707, 0.062_306_814, 155.236_3],
+        }
+    }
+
+    pub fn diamond() -> Self {
+        Dispersion::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030_625, 0.011_236, 0.0],
+        }
+    }
+}
@@ -1,8 +1,12 @@
 use crate::aabb::AABB;
 use crate::hittable::{HitRecord, Hittable};
+use crate::light::{Shape, ShapeSample};
+use crate::light_bvh::DirectionCone;
 use crate::material::Material;
+use crate::microfacet::Frame;
 use crate::ray::Ray;
-use crate::vec::vec;
+use crate::stats::{self, Counter};
+use crate::vec::{vec, vec3};
 
 use nalgebra::{Vector2, Vector3};
 use std::sync::Arc;
@@ -26,6 +30,7 @@ impl Sphere {
 
 impl Hittable for Sphere {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        stats::inc(Counter::PrimitiveTests);
         let oc = ray.origin() - self.center;
         let a = ray.direction().magnitude_squared();
         let half_b = oc.dot(&ray.direction());
@@ -80,3 +85,61 @@ fn get_sphere_uv(p: Vector3<f32>) -> Vector2<f32> {
     let v = (theta + f32::consts::PI / 2.0) / f32::consts::PI;
     Vector2::new(u, v)
 }
+
+// Inverse of get_sphere_uv, uv is longitude and latitude
+impl Shape for Sphere {
+    fn point(&self, uv: Vector2<f32>) -> (Vector3<f32>, Vector3<f32>, f32) {
+        let phi = (1.0 - uv.x) * 2.0 * f32::consts::PI - f32::consts::PI;
+        let theta = uv.y * f32::consts::PI - f32::consts::PI / 2.0;
+        let normal = vec(theta.cos() * phi.cos(), theta.sin(), theta.cos() * phi.sin());
+        let jacobian = 2.0 * f32::consts::PI * f32::consts::PI * self.radius * self.radius * theta.cos();
+        (self.center + normal * self.radius, normal, jacobian.max(0.0))
+    }
+
+    fn area(&self) -> f32 {
+        4.0 * f32::consts::PI * self.radius * self.radius
+    }
+
+    fn normal_bounds(&self) -> DirectionCone {
+        DirectionCone::entire_sphere()
+    }
+
+    // Uniform over the cone of directions the sphere subtends from outside
+    fn sample_solid_angle(&self, p: Vector3<f32>, u: Vector2<f32>) -> Option<ShapeSample> {
+        let to_center = self.center - p;
+        let distance_squared = to_center.magnitude_squared();
+        let sin2_max = self.radius * self.radius / distance_squared;
+        if sin2_max >= 1.0 {
+            return None;
+        }
+        let cos_max = (1.0 - sin2_max).sqrt();
+        // 1 - cos_max without cancellation for small cones
+        let one_minus_cos_max = sin2_max / (1.0 + cos_max);
+        let cos_theta = 1.0 - u.x * one_minus_cos_max;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * f32::consts::PI * u.y;
+        let direction = Frame::from_normal(to_center / distance_squared.sqrt())
+            .to_world(vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta));
+
+        // Nearest intersection, clamped to the silhouette against round-off
+        let along = direction.dot(&to_center);
+        let t = along - (self.radius * self.radius - (distance_squared - along * along)).max(0.0).sqrt();
+        let point = p + direction * t;
+        let normal = (point - self.center) / self.radius;
+        Some(ShapeSample {
+            point,
+            normal,
+            uv: get_sphere_uv(normal),
+            pdf: 1.0 / (2.0 * f32::consts::PI * one_minus_cos_max),
+        })
+    }
+
+    fn pdf_solid_angle(&self, p: Vector3<f32>, _point: Vector3<f32>) -> Option<f32> {
+        let sin2_max = self.radius * self.radius / (self.center - p).magnitude_squared();
+        if sin2_max >= 1.0 {
+            return None;
+        }
+        let cos_max = (1.0 - sin2_max).sqrt();
+        Some((1.0 + cos_max) / (2.0 * f32::consts::PI * sin2_max))
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Render statistics. Every thread counts into its own set of counters, so
+// the hot paths never contend on a shared cache line, and the per-thread
+// counters are only summed when a snapshot is taken.
+
+#[derive(Clone, Copy, Debug)]
+pub enum Counter {
+    CameraRays,
+    Bounces,
+    ShadowRays,
+    BvhNodeVisits,
+    PrimitiveTests,
+    VolumeEvents,
+}
+
+const NUM_COUNTERS: usize = 6;
+// The last bin also holds all longer paths
+pub const PATH_LENGTH_BINS: usize = 64;
+
+struct ThreadStats {
+    counters: [AtomicU64; NUM_COUNTERS],
+    path_lengths: [AtomicU64; PATH_LENGTH_BINS],
+}
+
+impl ThreadStats {
+    fn new() -> Self {
+        ThreadStats {
+            counters: std::array::from_fn(|_| AtomicU64::new(0)),
+            path_lengths: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+// Counters of all threads that have recorded anything, kept alive after a
+// thread exits so its counts aren't lost
+static REGISTRY: Mutex<Vec<Arc<ThreadStats>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static LOCAL: Arc<ThreadStats> = {
+        let stats = Arc::new(ThreadStats::new());
+        REGISTRY.lock().unwrap().push(Arc::clone(&stats));
+        stats
+    };
+}
+
+pub fn add(counter: Counter, n: u64) {
+    LOCAL.with(|s| s.counters[counter as usize].fetch_add(n, Ordering::Relaxed));
+}
+
+pub fn inc(counter: Counter) {
+    add(counter, 1);
+}
+
+// Number of segments in a finished path, camera ray included
+pub fn record_path_length(length: u32) {
+    let bin = (length as usize).min(PATH_LENGTH_BINS - 1);
+    LOCAL.with(|s| s.path_lengths[bin].fetch_add(1, Ordering::Relaxed));
+}
+
+pub fn snapshot() -> Stats {
+    let mut stats = Stats {
+        counters: [0; NUM_COUNTERS],
+        path_lengths: [0; PATH_LENGTH_BINS],
+    };
+    for thread in REGISTRY.lock().unwrap().iter() {
+        for (total, c) in stats.counters.iter_mut().zip(thread.counters.iter()) {
+            *total += c.load(Ordering::Relaxed);
+        }
+        for (total, c) in stats.path_lengths.iter_mut().zip(thread.path_lengths.iter()) {
+            *total += c.load(Ordering::Relaxed);
+        }
+    }
+    stats
+}
+
+#[derive(Clone, Copy)]
+pub struct Stats {
+    counters: [u64; NUM_COUNTERS],
+    path_lengths: [u64; PATH_LENGTH_BINS],
+}
+
+impl Stats {
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.counters[counter as usize]
+    }
+
+    pub fn total_rays(&self) -> u64 {
+        self.get(Counter::CameraRays) + self.get(Counter::Bounces) + self.get(Counter::ShadowRays)
+    }
+
+    // Counts accumulated since `earlier` was taken
+    pub fn since(&self, earlier: &Stats) -> Stats {
+        let mut delta = *self;
+        for (d, e) in delta.counters.iter_mut().zip(earlier.counters.iter()) {
+            *d -= e;
+        }
+        for (d, e) in delta.path_lengths.iter_mut().zip(earlier.path_lengths.iter()) {
+            *d -= e;
+        }
+        delta
+    }
+
+    pub fn mrays_per_sec(&self, elapsed: Duration) -> f64 {
+        self.total_rays() as f64 / 1e6 / elapsed.as_secs_f64().max(1e-9)
+    }
+
+    pub fn print_report(&self, render_time: Duration, passes: u32) {
+        let m = |counter| self.get(counter) as f64 / 1e6;
+        println!("---- Render statistics ----");
+        println!(
+            "Rays: {:.2} M camera, {:.2} M bounces, {:.2} M shadow, {:.2} M total",
+            m(Counter::CameraRays), m(Counter::Bounces), m(Counter::ShadowRays), self.total_rays() as f64 / 1e6
+        );
+        println!("Throughput: {:.2} Mrays/s", self.mrays_per_sec(render_time));
+        if passes > 0 {
+            println!("Time per pass: {:.2?}", render_time / passes);
+        }
+        println!(
+            "BVH node visits: {:.2} M, primitive tests: {:.2} M, volume events: {:.2} M",
+            m(Counter::BvhNodeVisits), m(Counter::PrimitiveTests), m(Counter::VolumeEvents)
+        );
+
+        let paths: u64 = self.path_lengths.iter().sum();
+        if paths == 0 {
+            return;
+        }
+        let max_count = *self.path_lengths.iter().max().unwrap();
+        let longest = self.path_lengths.iter().rposition(|&c| c > 0).unwrap();
+        println!("Path lengths:");
+        for (length, &count) in self.path_lengths.iter().enumerate().take(longest + 1).skip(1) {
+            let bar = "#".repeat((40 * count / max_count) as usize);
+            let label = if length == PATH_LENGTH_BINS - 1 { format!("{}+", length) } else { length.to_string() };
+            println!("{:>4} {:>6.2}% {}", label, 100.0 * count as f64 / paths as f64, bar);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use nalgebra::Vector3;
+use std::sync::Arc;
+
+use crate::hittable::HitRecord;
+use crate::material::{Dielectric, Material};
+use crate::medium_stack::{MediumEntry, MediumStack};
+use crate::ray::Ray;
+use crate::texture::ConstantTex;
+use crate::vec::vec;
+use crate::volume::HomogeneousMedium;
+
+// Subsurface scattering for skin, wax, marble and the like. The object is a
+// smooth or rough dielectric boundary filled with a scattering medium, the
+// random walk inside is done by the integrator like for any other interior
+// medium. The object has to be closed.
+//
+// Instead of raw coefficients the medium is set up from the color the surface
+// should have (the multiple scattering albedo) and the mean free path per
+// color channel in scene units, which controls how far light bleeds.
+pub struct Subsurface {
+    boundary: Dielectric,
+}
+
+impl Subsurface {
+    pub fn new(albedo: Vector3<f32>, mean_free_path: Vector3<f32>, ior: f32, roughness: f32) -> Self {
+        let (sigma_a, sigma_s) = coefficients(albedo, mean_free_path);
+        Subsurface {
+            boundary: Dielectric {
+                ref_idx: ior,
+                roughness: ConstantTex::new_arc(vec(roughness, roughness, roughness)),
+                medium: Some(Arc::new(HomogeneousMedium::new(sigma_a, sigma_s))),
+                ..Dielectric::default()
+            },
+        }
+    }
+
+    // Measured skin and marble (Jensen et al. 2001, "A Practical Model for
+    // Subsurface Light Transport"), scaled by the scene units per millimeter
+    pub fn skin(units_per_mm: f32) -> Self {
+        Subsurface::new(vec(0.44, 0.22, 0.13), vec(1.29, 0.95, 0.67) * units_per_mm, 1.3, 0.35)
+    }
+
+    pub fn marble(units_per_mm: f32) -> Self {
+        Subsurface::new(vec(0.83, 0.79, 0.75), vec(0.46, 0.38, 0.33) * units_per_mm, 1.5, 0.1)
+    }
+}
+
+// Single scattering albedo that gives the requested multiple scattering albedo
+// (Chiang et al. 2016, "Practical and Controllable Subsurface Scattering for
+// Production Path Tracing"), returns sigma_a and sigma_s
+pub fn coefficients(albedo: Vector3<f32>, mean_free_path: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let single_scattering = albedo.map(|a| {
+        let a = a.clamp(0.0, 0.999);
+        1.0 - (4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt()).powi(2)
+    });
+    let sigma_t = mean_free_path.map(|d| 1.0 / d.max(1e-6));
+    let sigma_s = sigma_t.component_mul(&single_scattering);
+    (sigma_t - sigma_s, sigma_s)
+}
+
+impl Material for Subsurface {
+    fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
+        self.boundary.scatter(ray, hit)
+    }
+
+    fn shadow_pass(&self, ray: &Ray, hit: &HitRecord) -> Option<Ray> {
+        self.boundary.shadow_pass(ray, hit)
+    }
+
+    fn eval(&self, ray: &Ray, hit: &HitRecord, direction: Vector3<f32>) -> Option<(Vector3<f32>, f32)> {
+        self.boundary.eval(ray, hit, direction)
+    }
+
+    fn medium_entry(&self, ray: &Ray, media: &MediumStack) -> Option<MediumEntry> {
+        self.boundary.medium_entry(ray, media)
+    }
+}
@@ -0,0 +1,235 @@
+use rayon::prelude::*;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Pixel rectangle, x1 and y1 are exclusive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Rect {
+    pub fn new(x0: u32, y0: u32, x1: u32, y1: u32) -> Self {
+        Rect { x0, y0, x1, y1 }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.x1.saturating_sub(self.x0)
+    }
+
+    pub fn height(&self) -> u32 {
+        self.y1.saturating_sub(self.y0)
+    }
+
+    pub fn area(&self) -> u32 {
+        self.width() * self.height()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.area() == 0
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+    }
+
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x0 = self.x0.max(other.x0);
+        let y0 = self.y0.max(other.y0);
+        Rect {
+            x0,
+            y0,
+            x1: self.x1.min(other.x1).max(x0),
+            y1: self.y1.min(other.y1).max(y0),
+        }
+    }
+
+    // Row-major pixel coordinates inside the rectangle
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32)> {
+        let (x0, x1) = (self.x0, self.x1);
+        (self.y0..self.y1).flat_map(move |y| (x0..x1).map(move |x| (x, y)))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+// Splits the image (or only the part inside `region`) into tiles of at most
+// `tile_size` x `tile_size` pixels, sorted in the order they should be rendered.
+pub fn generate_tiles(nx: u32, ny: u32, tile_size: u32, order: TileOrder, region: Option<Rect>) -> Vec<Rect> {
+    let image = Rect::new(0, 0, nx, ny);
+    let region = region.map_or(image, |r| r.intersect(&image));
+    let tiles_x = nx.div_ceil(tile_size);
+    let tiles_y = ny.div_ceil(tile_size);
+
+    let mut tiles = (0..tiles_y)
+        .flat_map(|j| (0..tiles_x).map(move |i| (i, j)))
+        .filter_map(|(i, j)| {
+            let tile = Rect::new(
+                i * tile_size,
+                j * tile_size,
+                ((i + 1) * tile_size).min(nx),
+                ((j + 1) * tile_size).min(ny),
+            )
+            .intersect(&region);
+            if tile.is_empty() { None } else { Some(((i, j), tile)) }
+        })
+        .collect::<Vec<((u32, u32), Rect)>>();
+
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Spiral => {
+            // Rings of tiles around the center, walked by angle within each ring
+            let cx = (tiles_x as f32 - 1.0) / 2.0;
+            let cy = (tiles_y as f32 - 1.0) / 2.0;
+            let key = |(i, j): (u32, u32)| {
+                let dx = i as f32 - cx;
+                let dy = j as f32 - cy;
+                let ring = dx.abs().max(dy.abs()).round();
+                (ring, dy.atan2(dx))
+            };
+            tiles.sort_by(|a, b| key(a.0).partial_cmp(&key(b.0)).unwrap());
+        }
+        TileOrder::Hilbert => {
+            let n = tiles_x.max(tiles_y).next_power_of_two();
+            tiles.sort_by_key(|((i, j), _)| hilbert_index(n, *i, *j));
+        }
+    }
+
+    tiles.into_iter().map(|(_, tile)| tile).collect()
+}
+
+// Distance along a Hilbert curve filling an n x n grid (n a power of two)
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u32 {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = ((x & s) > 0) as u32;
+        let ry = ((y & s) > 0) as u32;
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+// Hands out tiles in order to the rayon workers. Each worker keeps pulling the
+// next tile until the queue is empty, so tiles are started in the scheduled
+// order and faster workers pick up more of them.
+pub struct TileScheduler<'a> {
+    tiles: &'a [Rect],
+    next: AtomicUsize,
+    done: AtomicUsize,
+    pub report_progress: bool,
+}
+
+impl<'a> TileScheduler<'a> {
+    pub fn new(tiles: &'a [Rect]) -> Self {
+        TileScheduler {
+            tiles,
+            next: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+            report_progress: false,
+        }
+    }
+
+    pub fn render<T, F>(&self, render_tile: F) -> Vec<(Rect, T)>
+    where
+        T: Send,
+        F: Fn(&Rect) -> T + Sync,
+    {
+        self.next.store(0, Ordering::SeqCst);
+        self.done.store(0, Ordering::SeqCst);
+
+        let results = (0..rayon::current_num_threads())
+            .into_par_iter()
+            .flat_map(|_| {
+                let mut rendered = Vec::new();
+                loop {
+                    let i = self.next.fetch_add(1, Ordering::Relaxed);
+                    if i >= self.tiles.len() {
+                        break;
+                    }
+                    let tile = self.tiles[i];
+                    rendered.push((tile, render_tile(&tile)));
+
+                    let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+                    if self.report_progress {
+                        print!("\rtiles: {}/{}", done, self.tiles.len());
+                        let _ = io::stdout().flush();
+                    }
+                }
+                rendered
+            })
+            .collect();
+
+        if self.report_progress {
+            println!();
+        }
+        results
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum CropWindow {
+    Pixels(Rect),
+    // x0, y0, x1, y1 as fractions of the image size, (0, 0) is the top left corner
+    Normalized(f32, f32, f32, f32),
+}
+
+impl CropWindow {
+    pub fn to_rect(self, nx: u32, ny: u32) -> Rect {
+        let image = Rect::new(0, 0, nx, ny);
+        match self {
+            CropWindow::Pixels(rect) => rect.intersect(&image),
+            CropWindow::Normalized(x0, y0, x1, y1) => {
+                let to_px = |t: f32, n: u32| (t.clamp(0.0, 1.0) * n as f32).round() as u32;
+                Rect::new(to_px(x0, nx), to_px(y0, ny), to_px(x1, nx), to_px(y1, ny)).intersect(&image)
+            }
+        }
+    }
+}
+
+// What gets written to disk when rendering with a crop window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CropOutput {
+    // Full resolution image, everything outside the crop window is black
+    FullFrame,
+    // Only the crop window, with its position in the full frame recorded next to the image
+    Cropped,
+}
+
+// Copies the pixels inside `rect` out of a row-major buffer with `channels` values per pixel
+pub fn crop_buffer<T: Copy>(buf: &[T], nx: u32, channels: usize, rect: &Rect) -> Vec<T> {
+    rect.pixels()
+        .flat_map(|(x, y)| {
+            let offset = (y * nx + x) as usize * channels;
+            buf[offset..offset + channels].iter().copied()
+        })
+        .collect()
+}
+
+// Seed for the pixel jitter of one tile in one pass. Deriving it from the
+// render seed and pass index makes the sample positions reproducible, so a
+// resumed render continues the sequence instead of repeating samples.
+pub fn tile_seed(seed: u64, pass: u32, tile: &Rect) -> u64 {
+    let mut z = seed
+        ^ (pass as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ ((tile.x0 as u64) << 32 | tile.y0 as u64).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
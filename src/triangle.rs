@@ -2,6 +2,7 @@ use crate::aabb::AABB;
 use crate::hittable::{HitRecord, Hittable};
 use crate::material::Material;
 use crate::ray::Ray;
+use crate::stats::{self, Counter};
 use crate::vec::vec3;
 
 use nalgebra::{Vector2, Vector3};
@@ -39,6 +40,7 @@ impl Triangle {
 
 impl Hittable for Triangle {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        stats::inc(Counter::PrimitiveTests);
         let edge1 = self.v1 - self.v0;
         let edge2 = self.v2 - self.v0;
 
@@ -26,6 +26,15 @@ pub fn random_unit_vec() -> Vector3<f32> {
     Vector3::new(r*a.cos(), r*a.sin(), z)
 }
 
+// Cosine-weighted direction around +z
+pub fn random_cosine_direction() -> Vector3<f32> {
+    let mut rng = thread_rng();
+    let r1 = rng.gen::<f32>();
+    let phi = 2.0 * f32::consts::PI * rng.gen::<f32>();
+    let r = r1.sqrt();
+    Vector3::new(r * phi.cos(), r * phi.sin(), (1.0 - r1).sqrt())
+}
+
 pub fn random_unit_in_disk() -> Vector3<f32> {
     let mut rng = thread_rng();
     let mut p;
@@ -87,4 +96,8 @@ pub fn random_vec_range(a: f32, b: f32) -> Vector3<f32> {
 
 pub fn has_nan(v: &Vector3<f32>) -> bool {
     v.x.is_nan() || v.y.is_nan() || v.z.is_nan()
+}
+
+pub fn luminance(c: Vector3<f32>) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
 }
\ No newline at end of file
@@ -1,30 +1,60 @@
 use crate::aabb::AABB;
-use crate::hittable::{HitRecord, Hittable};
+use crate::hittable::{HitRecord, Hittable, Transform};
 use crate::material::{Material, Isotropic};
-use crate::texture::{ConstantTex, Texture, CheckerTex};
-use crate::ray::Ray;
-use crate::vec::{vec2, vec3, vec_one, vec_zero};
+use crate::medium_stack::{MediumEntry, MediumStack, MAX_NESTED_MEDIA};
+use crate::texture::{ConstantTex, Texture};
+use crate::ray::{Ray, RayKind};
+use crate::spectrum;
+use crate::sphere::Sphere;
+use crate::stats::{self, Counter};
+use crate::vec::{vec, vec2, vec_one, vec_zero};
 
-use nalgebra::{Vector2, Vector3};
+use nalgebra::{Rotation3, Vector3};
 use std::sync::Arc;
 use std::f32;
 use rand::{thread_rng, Rng};
 
 
+// Volumes in the scene. The boundary only marks where the medium starts and
+// ends: crossing it pushes or pops the medium on the ray's medium stack and the
+// integrator samples the medium between surfaces, so objects inside fog and
+// colored coefficients are handled correctly. Volumes may overlap and nest,
+// where they do their coefficients add up. The boundary should be closed, an
+// open one doesn't break anything as exits that weren't entered are ignored,
+// but the medium only fills paths that crossed a front face.
 pub struct ConstantMedium {
-    boundary:Arc<dyn Hittable>,
-    phase_function: Arc<dyn Material>,
-    neg_inv_density: f32
+    boundary: Arc<dyn Hittable>,
+    interface: Arc<dyn Material>,
 }
 
 impl ConstantMedium {
+    // Gray medium, the color comes from the albedo of the phase function material
     pub fn new(boundary: impl Hittable + 'static, density: f32, material: Arc<dyn Material>) -> Self {
+        ConstantMedium::chromatic(boundary, vec_zero(), vec_one() * density, material)
+    }
+
+    // Separate absorption and scattering coefficients per color channel, for
+    // chromatic fog, milky liquids and the like
+    pub fn chromatic(boundary: impl Hittable + 'static, sigma_a: Vector3<f32>, sigma_s: Vector3<f32>, material: Arc<dyn Material>) -> Self {
+        let medium = HomogeneousMedium { sigma_a, sigma_s, emission: vec_zero(), phase_function: material };
+        ConstantMedium::from_medium(boundary, medium)
+    }
+
+    // Any medium, e.g. a glowing HomogeneousMedium
+    pub fn from_medium(boundary: impl Hittable + 'static, medium: impl Medium + 'static) -> Self {
         Self {
             boundary: Arc::new(boundary),
-            phase_function: material,
-            neg_inv_density: -1.0 / density
+            interface: Arc::new(MediumInterface { medium: Arc::new(medium) }),
         }
     }
+
+    // Fog around the whole scene, e.g. a HeightFog. The sphere has to enclose
+    // the camera and everything that should be fogged, rays that leave it
+    // see the environment.
+    pub fn global(center: Vector3<f32>, radius: f32, medium: impl Medium + 'static) -> Self {
+        let placeholder = Arc::new(Isotropic { albedo: ConstantTex::new_arc(vec_one()) });
+        ConstantMedium::from_medium(Sphere::new(center, radius, placeholder), medium)
+    }
 }
 
 impl Hittable for ConstantMedium {
@@ -32,38 +62,9 @@ impl Hittable for ConstantMedium {
         if ray.albedo_normal_ray {
             return None;
         }
-        let mut rng = thread_rng();
-
-        if let Some(mut hit1) = self.boundary.hit(ray, f32::MIN, f32::MAX) {
-            if let Some(mut hit2) = self.boundary.hit(ray, hit1.t + 0.0001, f32::MAX) {
-                if hit1.t < t_min { hit1.t = t_min; }
-                if hit2.t > t_max { hit2.t = t_max; }
-
-                if hit1.t >= hit2.t { return None; }
-
-                if hit1.t < 0.0 { hit1.t = 0.0; }
-
-                let ray_length = ray.direction().magnitude();
-                let distance_inside_boundary = (hit2.t - hit1.t) * ray_length;
-                let hit_distance = self.neg_inv_density * rng.gen::<f32>().ln();
-
-                if hit_distance > distance_inside_boundary {
-                    // Extend ray to check for more hits in concave boundaries
-                    return self.hit(ray, hit2.t + 0.0001, f32::MAX);
-                }
-
-                let t = hit1.t + hit_distance / ray_length;
-
-                Some(HitRecord::new(
-                    t,
-                    ray.at(t),
-                    vec3(1.0, 0.0, 0.0),
-                    ray,
-                    Arc::clone(&self.phase_function),
-                    vec2(0.0, 0.0)))
-
-            } else { None }
-        } else { None }
+        let mut hit = self.boundary.hit(ray, t_min, t_max)?;
+        hit.material = Arc::clone(&self.interface);
+        Some(hit)
     }
 
     fn bounding_box(&self) -> Option<AABB> {
@@ -71,20 +72,63 @@ impl Hittable for ConstantMedium {
     }
 }
 
+// Density from the x channel of a texture, evaluated at world space positions
+// unless the volume is placed with `transform`. max_density has to bound the
+// texture.
 pub struct NonUniformMedium {
-    boundary:Arc<dyn Hittable>,
-    phase_function: Arc<dyn Material>,
-    density: Arc<dyn Texture>,
-    max_density: f32,
+    boundary: Arc<dyn Hittable>,
+    medium: Arc<dyn Medium>,
+    interface: Arc<dyn Material>,
 }
 
 impl NonUniformMedium {
     pub fn new(boundary: impl Hittable + 'static, density: Arc<dyn Texture>, max_density: f32, material: Arc<dyn Material>) -> Self {
+        NonUniformMedium::chromatic(boundary, density, max_density, vec_zero(), vec_one(), material)
+    }
+
+    // sigma_a and sigma_s are scaled by the density, the control density for
+    // shadow rays is the mean density over the boundary's bounding box
+    pub fn chromatic(boundary: impl Hittable + 'static, density: Arc<dyn Texture>, max_density: f32, sigma_a: Vector3<f32>, sigma_s: Vector3<f32>, material: Arc<dyn Material>) -> Self {
+        let mut medium = TexturedMedium {
+            density,
+            max_density,
+            control_density: 0.0,
+            sigma_a,
+            sigma_s,
+            emission: None,
+            temperature: None,
+            phase_function: material,
+        };
+        if let Some(bounds) = boundary.bounding_box() {
+            medium.control_density = medium.mean_density(&bounds);
+        }
+        NonUniformMedium::from_medium(boundary, medium)
+    }
+
+    // Any medium, e.g. a TexturedMedium with an emission or temperature field
+    pub fn from_medium(boundary: impl Hittable + 'static, medium: impl Medium + 'static) -> Self {
+        let medium: Arc<dyn Medium> = Arc::new(medium);
         Self {
             boundary: Arc::new(boundary),
-            phase_function: material,
-            density,
-            max_density
+            interface: Arc::new(MediumInterface { medium: Arc::clone(&medium) }),
+            medium,
+        }
+    }
+
+    // Moves and rotates boundary and medium together, so the density stays
+    // in the local space of the boundary. A Transform around the volume would
+    // only move the boundary.
+    pub fn transform(self, offset: Vector3<f32>, rotation_deg: Vector3<f32>) -> Self {
+        let boundary = Transform::new_b(self.boundary, offset, rotation_deg);
+        let medium: Arc<dyn Medium> = Arc::new(TransformedMedium {
+            medium: self.medium,
+            offset,
+            rotation: boundary.rotation,
+        });
+        Self {
+            boundary: Arc::new(boundary),
+            interface: Arc::new(MediumInterface { medium: Arc::clone(&medium) }),
+            medium,
         }
     }
 }
@@ -94,56 +138,557 @@ impl Hittable for NonUniformMedium {
         if ray.albedo_normal_ray {
             return None;
         }
-        let mut rng = thread_rng();
+        let mut hit = self.boundary.hit(ray, t_min, t_max)?;
+        hit.material = Arc::clone(&self.interface);
+        Some(hit)
+    }
 
-        if let Some(mut hit1) = self.boundary.hit(ray, f32::MIN, f32::MAX) {
-            if let Some(mut hit2) = self.boundary.hit(ray, hit1.t + 0.0001, f32::MAX) {
-                if hit1.t < t_min { hit1.t = t_min; }
-                if hit2.t > t_max { hit2.t = t_max; }
+    fn bounding_box(&self) -> Option<AABB> {
+        self.boundary.bounding_box()
+    }
+}
+
+// Invisible surface of a volume, rays continue straight and only enter or
+// leave the medium
+struct MediumInterface {
+    medium: Arc<dyn Medium>,
+}
 
-                if hit1.t >= hit2.t { return None; }
+impl Material for MediumInterface {
+    fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vector3<f32>)> {
+        Some((self.shadow_pass(ray, hit)?, vec_one()))
+    }
 
-                if hit1.t < 0.0 { hit1.t = 0.0; }
+    fn is_solid(&self) -> bool {
+        false
+    }
 
-                let ray_length = ray.direction().magnitude();
-                let distance_inside_boundary = (hit2.t - hit1.t) * ray_length;
-                if distance_inside_boundary.is_nan() {
-                    return None;
-                }
+    fn shadow_pass(&self, ray: &Ray, hit: &HitRecord) -> Option<Ray> {
+        let media = ray.media.clone().unwrap_or_default();
+        let entry = self.medium_entry(ray, &media)?;
+        let media = if hit.front_face {
+            media.entered(entry)
+        } else {
+            media.exited(entry.id)
+        };
+        let mut passed = Ray::new(hit.p, ray.direction());
+        passed.media = Some(media);
+        Some(passed)
+    }
 
-                let s_max = self.max_density;
-                let mut d = 0.0;
-                let t = loop {
-                    let x = rng.gen::<f32>();
-                    d += -(1.0 - x).ln() / s_max;
-                    let y = rng.gen::<f32>();
-                    if d > distance_inside_boundary {
-                        break 0.0;
-                    }
-                    let t = hit1.t + d / ray_length;
-                    if self.density.value(hit1.uv, ray.at(t)).x / s_max > y {
-                        break t;
+    // Volumes don't refract, the index of refraction stays the one outside
+    fn medium_entry(&self, _ray: &Ray, media: &MediumStack) -> Option<MediumEntry> {
+        Some(MediumEntry {
+            id: self as *const Self as usize,
+            priority: 0,
+            ior: media.ior(),
+            medium: Some(Arc::clone(&self.medium)),
+        })
+    }
+}
+
+// Interior of a closed object, e.g. the water inside a Dielectric. Unlike
+// ConstantMedium it isn't part of the scene geometry, the integrator samples it
+// for every path segment that starts inside the object.
+pub trait Medium: Sync + Send {
+    // Coefficients and emission at a point, at the ray's wavelengths
+    fn properties(&self, ray: &Ray, p: Vector3<f32>) -> MediumProperties;
+    // Bound on the extinction of every channel along the ray
+    fn majorant(&self, ray: &Ray) -> f32;
+    // Estimates the fraction of light that makes it from the ray origin to
+    // t_max, for shadow rays
+    fn transmittance(&self, ray: &Ray, t_max: f32) -> Vector3<f32>;
+    fn phase_function(&self) -> Arc<dyn Material>;
+}
+
+pub struct MediumProperties {
+    pub sigma_a: Vector3<f32>,
+    pub sigma_s: Vector3<f32>,
+    // Radiance emitted where the medium absorbs, None if it doesn't glow
+    pub emission: Option<Vector3<f32>>,
+}
+
+pub struct MediumSample {
+    pub event: MediumEvent,
+    // Radiance emitted along the ray up to the event, already weighted by the
+    // transmittance and at the ray's wavelengths in spectral mode
+    pub emitted: Vector3<f32>,
+}
+
+pub enum MediumEvent {
+    // phase_function is the one of the medium that scattered
+    Scatter { t: f32, weight: Vector3<f32>, phase_function: Arc<dyn Material> },
+    // The ray reached t_max, weight is the transmittance estimate
+    Pass { weight: Vector3<f32> },
+    Absorbed,
+}
+
+// Coefficients are per unit length, sigma_a absorbs and sigma_s scatters.
+// Emission is the radiance of the medium where it absorbs, so only media with
+// a sigma_a glow, and the denser they are the closer they get to it.
+pub struct HomogeneousMedium {
+    pub sigma_a: Vector3<f32>,
+    pub sigma_s: Vector3<f32>,
+    pub emission: Vector3<f32>,
+    pub phase_function: Arc<dyn Material>,
+}
+
+impl HomogeneousMedium {
+    pub fn new(sigma_a: Vector3<f32>, sigma_s: Vector3<f32>) -> Self {
+        HomogeneousMedium {
+            sigma_a,
+            sigma_s,
+            emission: vec_zero(),
+            phase_function: Arc::new(Isotropic { albedo: ConstantTex::new_arc(vec_one()) }),
+        }
+    }
+
+    // Colored glass or clear liquids, pure Beer-Lambert absorption
+    pub fn absorbing(sigma_a: Vector3<f32>) -> Self {
+        HomogeneousMedium::new(sigma_a, vec_zero())
+    }
+
+    // Glowing gas, e.g. a nebula
+    pub fn emissive(sigma_a: Vector3<f32>, sigma_s: Vector3<f32>, emission: Vector3<f32>) -> Self {
+        HomogeneousMedium { emission, ..HomogeneousMedium::new(sigma_a, sigma_s) }
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    fn properties(&self, ray: &Ray, _p: Vector3<f32>) -> MediumProperties {
+        let (sigma_a, sigma_s) = coefficients(ray, self.sigma_a, self.sigma_s);
+        let emission = (self.emission != vec_zero()).then(|| emission(ray, Some(self.emission), None));
+        MediumProperties { sigma_a, sigma_s, emission }
+    }
+
+    fn majorant(&self, ray: &Ray) -> f32 {
+        coefficients(ray, self.sigma_a + self.sigma_s, vec_zero()).0.max()
+    }
+
+    // Beer-Lambert, no need to estimate anything
+    fn transmittance(&self, ray: &Ray, t_max: f32) -> Vector3<f32> {
+        let sigma_t = coefficients(ray, self.sigma_a + self.sigma_s, vec_zero()).0;
+        let distance = t_max * ray.direction().magnitude();
+        sigma_t.map(|s| if s > 0.0 { (-s * distance).exp() } else { 1.0 })
+    }
+
+    fn phase_function(&self) -> Arc<dyn Material> {
+        Arc::clone(&self.phase_function)
+    }
+}
+
+// Coefficients scaled by a density read from the x channel of a texture at the
+// world space position. The density must never exceed max_density.
+// control_density should be close to the typical density, shadow rays only
+// have to estimate the difference to it which lowers their variance.
+//
+// For fire the temperature texture (Kelvin, x channel) gives blackbody
+// emission, the emission texture tints and scales it or, without a
+// temperature, is the emitted radiance itself. Like for HomogeneousMedium it
+// is emitted where the medium absorbs.
+pub struct TexturedMedium {
+    pub density: Arc<dyn Texture>,
+    pub max_density: f32,
+    pub control_density: f32,
+    pub sigma_a: Vector3<f32>,
+    pub sigma_s: Vector3<f32>,
+    pub emission: Option<Arc<dyn Texture>>,
+    pub temperature: Option<Arc<dyn Texture>>,
+    pub phase_function: Arc<dyn Material>,
+}
+
+impl TexturedMedium {
+    fn density(&self, p: Vector3<f32>) -> f32 {
+        self.density.value(vec2(0.0, 0.0), p).x.clamp(0.0, self.max_density)
+    }
+
+    // Average over a grid of points in bounds, a good control_density
+    pub fn mean_density(&self, bounds: &AABB) -> f32 {
+        const STEPS: usize = 8;
+        let size = bounds.max - bounds.min;
+        let mut total = 0.0;
+        for i in 0..STEPS * STEPS * STEPS {
+            let cell = vec((i % STEPS) as f32, (i / STEPS % STEPS) as f32, (i / (STEPS * STEPS)) as f32);
+            let p = bounds.min + size.component_mul(&((cell + vec(0.5, 0.5, 0.5)) / STEPS as f32));
+            total += self.density(p);
+        }
+        total / (STEPS * STEPS * STEPS) as f32
+    }
+}
+
+impl Medium for TexturedMedium {
+    fn properties(&self, ray: &Ray, p: Vector3<f32>) -> MediumProperties {
+        let (sigma_a, sigma_s) = coefficients(ray, self.sigma_a, self.sigma_s);
+        let density = self.density(p);
+        let color = self.emission.as_ref().map(|e| e.value(vec2(0.0, 0.0), p));
+        let temperature = self.temperature.as_ref().map(|t| t.value(vec2(0.0, 0.0), p).x);
+        MediumProperties {
+            sigma_a: sigma_a * density,
+            sigma_s: sigma_s * density,
+            emission: (color.is_some() || temperature.is_some()).then(|| emission(ray, color, temperature)),
+        }
+    }
+
+    fn majorant(&self, ray: &Ray) -> f32 {
+        self.max_density * coefficients(ray, self.sigma_a + self.sigma_s, vec_zero()).0.max()
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: f32) -> Vector3<f32> {
+        let sigma_t = coefficients(ray, self.sigma_a + self.sigma_s, vec_zero()).0;
+        residual_ratio_tracking(ray, t_max, sigma_t, self.control_density, self.max_density, |p| self.density(p))
+    }
+
+    fn phase_function(&self) -> Arc<dyn Material> {
+        Arc::clone(&self.phase_function)
+    }
+}
+
+// A medium moved and rotated like Transform moves objects, the inner medium
+// sees rays and positions in its local space
+pub struct TransformedMedium {
+    pub medium: Arc<dyn Medium>,
+    pub offset: Vector3<f32>,
+    pub rotation: Rotation3<f32>,
+}
+
+impl TransformedMedium {
+    // Same parameterization as the world space ray, the transform is rigid
+    fn local_ray(&self, ray: &Ray) -> Ray {
+        let inv_rot = self.rotation.inverse();
+        let mut local = Ray::new(inv_rot * (ray.origin() - self.offset), inv_rot * ray.direction()).with_kind(ray.kind);
+        local.wavelengths = ray.wavelengths;
+        local
+    }
+}
+
+impl Medium for TransformedMedium {
+    fn properties(&self, ray: &Ray, p: Vector3<f32>) -> MediumProperties {
+        self.medium.properties(&self.local_ray(ray), self.rotation.inverse() * (p - self.offset))
+    }
+
+    fn majorant(&self, ray: &Ray) -> f32 {
+        self.medium.majorant(&self.local_ray(ray))
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: f32) -> Vector3<f32> {
+        self.medium.transmittance(&self.local_ray(ray), t_max)
+    }
+
+    fn phase_function(&self) -> Arc<dyn Material> {
+        self.medium.phase_function()
+    }
+}
+
+// Atmospheric fog that thins out exponentially with height, falloff is per
+// unit of height and 0 gives homogeneous fog. Below base_height the
+// coefficients stay at their full value.
+pub struct HeightFog {
+    pub sigma_a: Vector3<f32>,
+    pub sigma_s: Vector3<f32>,
+    pub base_height: f32,
+    pub falloff: f32,
+    pub phase_function: Arc<dyn Material>,
+}
+
+impl HeightFog {
+    pub fn new(sigma_s: Vector3<f32>, base_height: f32, falloff: f32) -> Self {
+        HeightFog {
+            sigma_a: vec_zero(),
+            sigma_s,
+            base_height,
+            falloff,
+            phase_function: Arc::new(Isotropic { albedo: ConstantTex::new_arc(vec_one()) }),
+        }
+    }
+
+    fn density(&self, p: Vector3<f32>) -> f32 {
+        self.density_at_height(p.y)
+    }
+
+    fn density_at_height(&self, y: f32) -> f32 {
+        (-self.falloff * (y - self.base_height).max(0.0)).exp()
+    }
+
+    // The density only changes with height, so along a straight segment it
+    // is bounded by its values at the ends. The lower one is the control
+    // density for shadow rays, which then only estimate a positive residual.
+    fn density_range(&self, ray: &Ray, t_max: f32) -> (f32, f32) {
+        let start = self.density(ray.origin());
+        let end = self.density_at_height(ray.origin().y + ray.direction().y * t_max);
+        // Infinite rays with no falloff or going level
+        let end = if end.is_nan() { start } else { end };
+        (start.min(end), start.max(end))
+    }
+
+    // Rays going up only get thinner fog, the others can reach the base
+    fn max_density(&self, ray: &Ray) -> f32 {
+        if ray.direction().y >= 0.0 {
+            self.density(ray.origin())
+        } else {
+            1.0
+        }
+    }
+}
+
+impl Medium for HeightFog {
+    fn properties(&self, ray: &Ray, p: Vector3<f32>) -> MediumProperties {
+        let (sigma_a, sigma_s) = coefficients(ray, self.sigma_a, self.sigma_s);
+        let density = self.density(p);
+        MediumProperties { sigma_a: sigma_a * density, sigma_s: sigma_s * density, emission: None }
+    }
+
+    fn majorant(&self, ray: &Ray) -> f32 {
+        self.max_density(ray) * coefficients(ray, self.sigma_a + self.sigma_s, vec_zero()).0.max()
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: f32) -> Vector3<f32> {
+        let sigma_t = coefficients(ray, self.sigma_a + self.sigma_s, vec_zero()).0;
+        let (control_density, max_density) = self.density_range(ray, t_max);
+        residual_ratio_tracking(ray, t_max, sigma_t, control_density, max_density, |p| self.density(p))
+    }
+
+    fn phase_function(&self) -> Arc<dyn Material> {
+        Arc::clone(&self.phase_function)
+    }
+}
+
+// RGB coefficients at the ray's wavelengths in spectral mode
+pub fn coefficients(ray: &Ray, sigma_a: Vector3<f32>, sigma_s: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    match &ray.wavelengths {
+        Some(wavelengths) => (spectrum::upsample(sigma_a, wavelengths), spectrum::upsample(sigma_s, wavelengths)),
+        None => (sigma_a, sigma_s),
+    }
+}
+
+// Radiance from an RGB color and a temperature, each optional, at the ray's
+// wavelengths in spectral mode
+pub fn emission(ray: &Ray, color: Option<Vector3<f32>>, temperature: Option<f32>) -> Vector3<f32> {
+    let color = color.unwrap_or_else(vec_one);
+    match (&ray.wavelengths, temperature) {
+        (Some(wavelengths), Some(kelvin)) => spectrum::upsample(color, wavelengths).component_mul(&spectrum::blackbody_spectrum(kelvin, wavelengths)),
+        (Some(wavelengths), None) => spectrum::upsample(color, wavelengths),
+        (None, Some(kelvin)) => color.component_mul(&spectrum::blackbody(kelvin)),
+        (None, None) => color,
+    }
+}
+
+fn average(v: Vector3<f32>) -> f32 {
+    (v.x + v.y + v.z) / 3.0
+}
+
+// Spectral tracking (Kutz et al. 2017, "Spectral and Decomposition Tracking for
+// Rendering Heterogeneous Volumes"). Tentative collisions are sampled with a
+// majorant and each one is classified as absorption, scattering or a null
+// collision with probabilities proportional to the current path weight. This
+// is a form of spectral MIS: colored coefficients and spectral mode need no
+// special handling and stay unbiased, a channel with a much lower extinction
+// than the majorant only costs more null collisions.
+//
+// Overlapping media are tracked together: the majorant is the sum of theirs,
+// coefficients add up and scattering is split per medium so the one that
+// scattered provides the phase function.
+//
+// Emission is collected at every tentative collision, weighted by sigma_a over
+// the majorant. That estimates the integral of transmittance * sigma_a *
+// emission along the ray without having to wait for an absorption (pbrt-v4,
+// "Volume Scattering Integrator").
+pub fn sample(media: &[Arc<dyn Medium>], ray: &Ray, t_max: f32) -> MediumSample {
+    let media = &media[..media.len().min(MAX_NESTED_MEDIA)];
+    let majorant: f32 = media.iter().map(|m| m.majorant(ray)).sum();
+    let ray_length = ray.direction().magnitude();
+    let mut emitted = vec_zero();
+    if majorant <= 0.0 || ray_length == 0.0 {
+        return MediumSample { event: MediumEvent::Pass { weight: vec_one() }, emitted };
+    }
+
+    let mut rng = thread_rng();
+    let mut weight = vec_one();
+    let mut t = 0.0;
+    loop {
+        t -= (1.0 - rng.gen::<f32>()).ln() / (majorant * ray_length);
+        if t >= t_max {
+            return MediumSample { event: MediumEvent::Pass { weight }, emitted };
+        }
+        stats::inc(Counter::VolumeEvents);
+
+        let p = ray.at(t);
+        let mut absorption = vec_zero();
+        let mut scattering = [vec_zero(); MAX_NESTED_MEDIA];
+        for (i, medium) in media.iter().enumerate() {
+            let properties = medium.properties(ray, p);
+            if let Some(radiance) = properties.emission {
+                emitted += weight.component_mul(&properties.sigma_a).component_mul(&radiance) / majorant;
+            }
+            absorption += properties.sigma_a;
+            scattering[i] = properties.sigma_s;
+        }
+        let scattering = &scattering[..media.len()];
+        let total_scattering: Vector3<f32> = scattering.iter().sum();
+        let null = (vec_one() * majorant - absorption - total_scattering).map(|x| x.max(0.0));
+
+        let p_absorb = average(absorption.component_mul(&weight));
+        let p_scatter = average(total_scattering.component_mul(&weight));
+        let p_null = average(null.component_mul(&weight));
+        let total = p_absorb + p_scatter + p_null;
+        if total <= 0.0 {
+            return MediumSample { event: MediumEvent::Absorbed, emitted };
+        }
+
+        let u = rng.gen::<f32>() * total;
+        if u < p_absorb {
+            return MediumSample { event: MediumEvent::Absorbed, emitted };
+        } else if u < p_absorb + p_scatter {
+            // Pick the medium that scattered, falling back to the last
+            // candidate when rounding leaves u past the end
+            let mut u = u - p_absorb;
+            let mut chosen = None;
+            for (i, sigma_s) in scattering.iter().enumerate() {
+                let p_medium = average(sigma_s.component_mul(&weight));
+                if p_medium > 0.0 {
+                    chosen = Some((i, p_medium));
+                    if u < p_medium {
+                        break;
                     }
+                }
+                u -= p_medium;
+            }
+            if let Some((i, p_medium)) = chosen {
+                weight = weight.component_mul(&scattering[i]) * (total / (majorant * p_medium));
+                return MediumSample {
+                    event: MediumEvent::Scatter { t, weight, phase_function: media[i].phase_function() },
+                    emitted,
                 };
+            }
+            return MediumSample { event: MediumEvent::Absorbed, emitted };
+        } else {
+            weight = weight.component_mul(&null) * (total / (majorant * p_null));
+        }
+    }
+}
 
-                if d > distance_inside_boundary {
-                    // Extend ray to check for more hits in concave boundaries
-                    return self.hit(ray, hit2.t + 0.0001, f32::MAX);
-                }
+// Overlapping media attenuate independently, so the estimates just multiply
+pub fn transmittance(media: &[Arc<dyn Medium>], ray: &Ray, t_max: f32) -> Vector3<f32> {
+    let mut transmittance = vec_one();
+    for medium in media {
+        transmittance = transmittance.component_mul(&medium.transmittance(ray, t_max));
+        if transmittance.max() <= 0.0 {
+            break;
+        }
+    }
+    transmittance
+}
 
-                Some(HitRecord::new(
-                    t,
-                    ray.at(t),
-                    vec3(1.0, 0.0, 0.0),
-                    ray,
-                    Arc::clone(&self.phase_function),
-                    vec2(0.0, 0.0)))
+// Residual ratio tracking (Novak et al. 2014, "Residual Ratio Tracking for
+// Estimating Attenuation in Participating Media"). The transmittance of a
+// constant control density is known analytically, only the residual is
+// estimated with ratio tracking. Unlike delta tracking every tentative collision
+// multiplies the estimate by the probability of a null collision instead of
+// terminating, so the result is smooth instead of 0 or 1. A control density of
+// zero gives plain ratio tracking.
+pub fn residual_ratio_tracking(ray: &Ray, t_max: f32, sigma_t: Vector3<f32>, control_density: f32, max_density: f32, density: impl Fn(Vector3<f32>) -> f32) -> Vector3<f32> {
+    let ray_length = ray.direction().magnitude();
+    let sigma_c = control_density * sigma_t;
+    let distance = t_max * ray_length;
+    let mut transmittance = sigma_c.map(|s| if s > 0.0 { (-s * distance).exp() } else { 1.0 });
 
-            } else { None }
-        } else { None }
+    let majorant = (max_density - control_density).abs().max(control_density) * sigma_t.max();
+    if majorant <= 0.0 || ray_length == 0.0 {
+        return transmittance;
     }
 
-    fn bounding_box(&self) -> Option<AABB> {
-        self.boundary.bounding_box()
+    let mut rng = thread_rng();
+    let mut t = 0.0;
+    loop {
+        if transmittance.max() <= 0.0 {
+            return transmittance;
+        }
+        t -= (1.0 - rng.gen::<f32>()).ln() / (majorant * ray_length);
+        if t >= t_max {
+            return transmittance;
+        }
+        stats::inc(Counter::VolumeEvents);
+        let residual = (density(ray.at(t)) - control_density) * sigma_t;
+        transmittance = transmittance.component_mul(&residual.map(|r| 1.0 - r / majorant));
+
+        // Russian roulette once the estimate is small, long rays through thick
+        // media would otherwise take forever
+        let max = transmittance.max();
+        if max < 0.1 {
+            let survival = max / 0.1;
+            if rng.gen::<f32>() >= survival {
+                return vec_zero();
+            }
+            transmittance /= survival;
+        }
+    }
+}
+
+// Fraction of light that arrives at t_max along a shadow ray. The media on the
+// ray's stack are estimated segment by segment, medium boundaries and other
+// surfaces that let shadow rays through are crossed and everything else blocks.
+pub fn shadow_transmittance(world: &Arc<dyn Hittable>, ray: &Ray, t_max: f32) -> Vector3<f32> {
+    stats::inc(Counter::ShadowRays);
+    let mut ray = ray.clone();
+    ray.kind = RayKind::Shadow;
+    let mut t_max = t_max;
+    let mut transmittance = vec_one();
+    loop {
+        let hit = world.hit(&ray, 0.001, t_max);
+        let t_end = hit.as_ref().map_or(t_max, |h| h.t);
+        if let Some(media) = &ray.media {
+            transmittance = transmittance.component_mul(&self::transmittance(&media.media(), &ray, t_end));
+            if transmittance.max() <= 0.0 {
+                return vec_zero();
+            }
+        }
+
+        let hit = match hit {
+            Some(hit) => hit,
+            None => return transmittance,
+        };
+        match hit.material.shadow_pass(&ray, &hit) {
+            Some(mut next) => {
+                next.wavelengths = ray.wavelengths;
+                next.kind = ray.kind;
+                t_max -= hit.t;
+                ray = next;
+            }
+            None => return vec_zero(),
+        }
+    }
+}
+
+// Media around a point, e.g. the camera. A probe starts outside the scene and
+// walks to the point, entering and leaving objects at every medium boundary
+// on the way just like a path would, so nested and overlapping volumes and
+// dielectrics give the same stack as rays that travelled there. The probe has
+// no wavelengths, so dispersive dielectrics enter with their base IOR.
+pub fn media_at(world: &Arc<dyn Hittable>, p: Vector3<f32>) -> MediumStack {
+    let mut media = MediumStack::default();
+    let bounds = match world.bounding_box() {
+        Some(bounds) => bounds,
+        None => return media,
+    };
+    // Oblique so the probe doesn't graze axis aligned walls and edges
+    let direction = Vector3::new(0.267, 0.873, 0.408).normalize();
+    let distance = (bounds.max - bounds.min).magnitude() + (p - bounds.min).magnitude() + 1.0;
+    let mut ray = Ray::new(p + direction * distance, -direction).with_kind(RayKind::Probe);
+    let mut t_max = distance;
+    // Bounded in case the probe keeps hitting the same surface
+    for _ in 0..1024 {
+        let hit = match world.hit(&ray, 0.001, t_max) {
+            Some(hit) => hit,
+            None => break,
+        };
+        if let Some(entry) = hit.material.medium_entry(&ray, &media) {
+            media = if hit.front_face {
+                media.entered(entry)
+            } else {
+                media.left(entry.id)
+            };
+        }
+        ray = Ray::new(hit.p, -direction).with_kind(RayKind::Probe);
+        t_max -= hit.t;
     }
+    media
 }
@@ -0,0 +1,512 @@
+use nalgebra::{Vector2, Vector3};
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::aabb::AABB;
+use crate::aabox::AABox;
+use crate::hittable::Transform;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::texture::Texture;
+use crate::volume::{coefficients, emission, residual_ratio_tracking, Medium, MediumProperties, NonUniformMedium};
+use crate::vec::{vec, vec_one, vec_zero};
+
+// Voxel grids for smoke, clouds and fire. The voxels are stored sparsely in
+// bricks of BRICK^3 voxels like the leaf nodes of OpenVDB, bricks that are
+// entirely zero aren't allocated. Every brick also keeps the maximum value
+// that trilinear interpolation can return inside it, which bounds the
+// majorant along a ray, and the mean of its voxels, which is the control
+// density for shadow rays.
+//
+// Grids are loaded from NRRD files (raw encoding, float or 8/16 bit integer
+// samples) or from headerless raw files of little endian f32. OpenVDB files
+// have to be converted first, e.g. with vdb_tool or a small script around
+// pyopenvdb's copyToArray.
+
+pub const BRICK: usize = 8;
+const BRICK_VOXELS: usize = BRICK * BRICK * BRICK;
+
+pub struct VoxelGrid {
+    pub dims: [usize; 3],
+    // Object space box covered by the grid
+    pub bounds: AABB,
+    brick_dims: [usize; 3],
+    bricks: Vec<Option<Box<[f32; BRICK_VOXELS]>>>,
+    brick_max: Vec<f32>,
+    brick_mean: Vec<f32>,
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl VoxelGrid {
+    // Builds a grid from x-fastest dense data, mapped onto `bounds`
+    pub fn from_dense(dims: [usize; 3], data: &[f32], bounds: AABB) -> Self {
+        assert!(!dims.contains(&0), "empty voxel grid");
+        assert_eq!(data.len(), dims[0] * dims[1] * dims[2]);
+        let brick_dims = [dims[0].div_ceil(BRICK), dims[1].div_ceil(BRICK), dims[2].div_ceil(BRICK)];
+        let num_bricks = brick_dims[0] * brick_dims[1] * brick_dims[2];
+        let mut grid = VoxelGrid {
+            dims,
+            bounds,
+            brick_dims,
+            bricks: (0..num_bricks).map(|_| None).collect(),
+            brick_max: vec![0.0; num_bricks],
+            brick_mean: vec![0.0; num_bricks],
+        };
+
+        for z in 0..dims[2] {
+            for y in 0..dims[1] {
+                for x in 0..dims[0] {
+                    let value = data[(z * dims[1] + y) * dims[0] + x];
+                    if value != 0.0 {
+                        let (b, i) = grid.brick_index(x, y, z);
+                        grid.bricks[b].get_or_insert_with(|| Box::new([0.0; BRICK_VOXELS]))[i] = value;
+                        grid.brick_mean[b] += value;
+                    }
+                }
+            }
+        }
+
+        // Bricks at the far edges are only partially filled
+        for bz in 0..brick_dims[2] {
+            for by in 0..brick_dims[1] {
+                for bx in 0..brick_dims[0] {
+                    let size = |b: usize, n: usize| (n - b * BRICK).min(BRICK);
+                    let voxels = size(bx, dims[0]) * size(by, dims[1]) * size(bz, dims[2]);
+                    grid.brick_mean[(bz * brick_dims[1] + by) * brick_dims[0] + bx] /= voxels as f32;
+                }
+            }
+        }
+
+        // Interpolation reaches one voxel into the neighbouring bricks
+        for z in 0..dims[2] {
+            for y in 0..dims[1] {
+                for x in 0..dims[0] {
+                    let value = data[(z * dims[1] + y) * dims[0] + x];
+                    if value <= 0.0 {
+                        continue;
+                    }
+                    let range = |c: usize, n: usize| (c.saturating_sub(1) / BRICK)..=((c + 1).min(n - 1) / BRICK);
+                    for bz in range(z, dims[2]) {
+                        for by in range(y, dims[1]) {
+                            for bx in range(x, dims[0]) {
+                                let b = (bz * brick_dims[1] + by) * brick_dims[0] + bx;
+                                grid.brick_max[b] = grid.brick_max[b].max(value);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    pub fn load(path: &str, bounds: AABB) -> io::Result<Self> {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("nrrd") | Some("nhdr") => VoxelGrid::load_nrrd(path, bounds),
+            Some("vdb") => Err(invalid_data(format!("{}: OpenVDB files aren't supported, convert the grid to NRRD", path))),
+            _ => Err(invalid_data(format!("{}: unknown voxel grid format, use load_raw for headerless files", path))),
+        }
+    }
+
+    pub fn load_raw(path: &str, dims: [usize; 3], bounds: AABB) -> io::Result<Self> {
+        let (count, size) = data_size(path, dims, "float")?;
+        check_length(path, size, fs::metadata(path)?.len())?;
+        let bytes = fs::read(path)?;
+        let data = decode_samples(&bytes, "float", true, count)?;
+        Ok(VoxelGrid::from_dense(dims, &data, bounds))
+    }
+
+    pub fn load_nrrd(path: &str, bounds: AABB) -> io::Result<Self> {
+        let mut reader = io::BufReader::new(fs::File::open(path)?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if !line.starts_with("NRRD") {
+            return Err(invalid_data(format!("{}: not a NRRD file", path)));
+        }
+
+        let mut sample_type = String::from("float");
+        let mut sizes = Vec::new();
+        let mut encoding = String::from("raw");
+        let mut little_endian = true;
+        let mut data_file = None;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                let value = value.trim_start_matches('=').trim();
+                match key.trim() {
+                    "type" => sample_type = value.to_string(),
+                    "sizes" => {
+                        sizes = value.split_whitespace()
+                            .map(|s| s.parse::<usize>().map_err(|e| invalid_data(format!("{}: bad sizes: {}", path, e))))
+                            .collect::<io::Result<Vec<_>>>()?
+                    }
+                    "encoding" => encoding = value.to_string(),
+                    "endian" => little_endian = value == "little",
+                    "data file" | "datafile" => data_file = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+        }
+
+        if sizes.len() != 3 {
+            return Err(invalid_data(format!("{}: expected a 3D grid, got sizes {:?}", path, sizes)));
+        }
+        if encoding != "raw" {
+            return Err(invalid_data(format!("{}: unsupported encoding {}", path, encoding)));
+        }
+        // The sizes come from the file, so they are checked against the
+        // length of the data before anything is allocated for them
+        let dims = [sizes[0], sizes[1], sizes[2]];
+        let (count, size) = data_size(path, dims, &sample_type)?;
+        let bytes = match data_file {
+            Some(file) => {
+                let data_path = Path::new(path).with_file_name(file);
+                check_length(path, size, fs::metadata(&data_path)?.len())?;
+                fs::read(data_path)?
+            }
+            None => {
+                let mut bytes = Vec::new();
+                reader.take(size as u64).read_to_end(&mut bytes)?;
+                check_length(path, size, bytes.len() as u64)?;
+                bytes
+            }
+        };
+        let data = decode_samples(&bytes, &sample_type, little_endian, count)?;
+        Ok(VoxelGrid::from_dense(dims, &data, bounds))
+    }
+
+    fn brick_index(&self, x: usize, y: usize, z: usize) -> (usize, usize) {
+        let b = ((z / BRICK) * self.brick_dims[1] + y / BRICK) * self.brick_dims[0] + x / BRICK;
+        let i = ((z % BRICK) * BRICK + y % BRICK) * BRICK + x % BRICK;
+        (b, i)
+    }
+
+    pub fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        let (b, i) = self.brick_index(x, y, z);
+        self.bricks[b].as_ref().map_or(0.0, |brick| brick[i])
+    }
+
+    pub fn max_value(&self) -> f32 {
+        self.brick_max.iter().cloned().fold(0.0, f32::max)
+    }
+
+    // Object space position to continuous voxel coordinates
+    pub fn to_grid(&self, p: Vector3<f32>) -> Vector3<f32> {
+        let size = self.bounds.max - self.bounds.min;
+        let dims = vec(self.dims[0] as f32, self.dims[1] as f32, self.dims[2] as f32);
+        (p - self.bounds.min).component_div(&size).component_mul(&dims)
+    }
+
+    // Trilinearly interpolated value, voxel centers are at half integer coordinates
+    pub fn lookup(&self, p: Vector3<f32>) -> f32 {
+        let g = self.to_grid(p) - vec(0.5, 0.5, 0.5);
+        let base = g.map(f32::floor);
+        let f = g - base;
+        let coord = |c: f32, i: usize, n: usize| (c as isize + i as isize).clamp(0, n as isize - 1) as usize;
+
+        let mut value = 0.0;
+        for corner in 0..8 {
+            let (i, j, k) = (corner & 1, (corner >> 1) & 1, corner >> 2);
+            let w = (if i == 1 { f.x } else { 1.0 - f.x })
+                * (if j == 1 { f.y } else { 1.0 - f.y })
+                * (if k == 1 { f.z } else { 1.0 - f.z });
+            if w > 0.0 {
+                value += w * self.voxel(
+                    coord(base.x, i, self.dims[0]),
+                    coord(base.y, j, self.dims[1]),
+                    coord(base.z, k, self.dims[2]),
+                );
+            }
+        }
+        value
+    }
+
+    // Maximum and mean of the values inside a brick
+    fn brick_range(&self, brick: [usize; 3]) -> (f32, f32) {
+        let b = (brick[2] * self.brick_dims[1] + brick[1]) * self.brick_dims[0] + brick[0];
+        (self.brick_max[b], self.brick_mean[b])
+    }
+
+    // Walks the bricks the ray crosses inside the bounds between t_min and
+    // t_max (Amanatides and Woo), calling `visit` with each brick and the
+    // part of the ray inside it until it returns false
+    fn walk_bricks(&self, ray: &Ray, t_min: f32, t_max: f32, mut visit: impl FnMut([usize; 3], f32, f32) -> bool) {
+        let (t_min, t_max) = match self.bounds.clip(ray, t_min, t_max) {
+            Some(range) => range,
+            None => return,
+        };
+        let to_brick = self.to_grid(ray.origin()) / BRICK as f32;
+        let dir = (self.to_grid(ray.origin() + ray.direction()) - self.to_grid(ray.origin())) / BRICK as f32;
+        let start = to_brick + dir * t_min;
+        let mut cell = [0usize; 3];
+        let mut step = [0isize; 3];
+        let mut t_next = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for a in 0..3 {
+            cell[a] = (start[a].max(0.0) as usize).min(self.brick_dims[a] - 1);
+            if dir[a] > 0.0 {
+                step[a] = 1;
+                t_next[a] = (cell[a] as f32 + 1.0 - to_brick[a]) / dir[a];
+                t_delta[a] = 1.0 / dir[a];
+            } else if dir[a] < 0.0 {
+                step[a] = -1;
+                t_next[a] = (cell[a] as f32 - to_brick[a]) / dir[a];
+                t_delta[a] = -1.0 / dir[a];
+            }
+        }
+
+        let mut t = t_min;
+        loop {
+            let axis = (0..3).min_by(|&a, &b| t_next[a].total_cmp(&t_next[b])).unwrap();
+            let t_exit = t_next[axis].min(t_max);
+            if t_exit > t && !visit(cell, t, t_exit) {
+                return;
+            }
+            t = t_exit;
+            if t >= t_max {
+                return;
+            }
+            let next = cell[axis] as isize + step[axis];
+            if next < 0 || next >= self.brick_dims[axis] as isize {
+                return;
+            }
+            cell[axis] = next as usize;
+            t_next[axis] += t_delta[axis];
+        }
+    }
+}
+
+fn sample_size(sample_type: &str) -> io::Result<usize> {
+    match sample_type {
+        "uchar" | "unsigned char" | "uint8" | "uint8_t" => Ok(1),
+        "ushort" | "unsigned short" | "uint16" | "uint16_t" => Ok(2),
+        "float" => Ok(4),
+        _ => Err(invalid_data(format!("unsupported sample type {}", sample_type))),
+    }
+}
+
+// Number of voxels and bytes of samples of a grid, rejecting empty grids and
+// sizes that overflow
+fn data_size(path: &str, dims: [usize; 3], sample_type: &str) -> io::Result<(usize, usize)> {
+    if dims.contains(&0) {
+        return Err(invalid_data(format!("{}: empty grid {:?}", path, dims)));
+    }
+    let size = sample_size(sample_type)?;
+    let count = dims[0].checked_mul(dims[1]).and_then(|n| n.checked_mul(dims[2]));
+    match count.and_then(|count| Some((count, count.checked_mul(size)?))) {
+        Some(sizes) => Ok(sizes),
+        None => Err(invalid_data(format!("{}: grid {:?} is too large", path, dims))),
+    }
+}
+
+fn check_length(path: &str, expected: usize, length: u64) -> io::Result<()> {
+    if length < expected as u64 {
+        return Err(invalid_data(format!("{}: expected {} bytes of samples, got {}", path, expected, length)));
+    }
+    Ok(())
+}
+
+// Converts the sample bytes of a grid file to f32, integer types are
+// normalized to [0, 1]
+fn decode_samples(bytes: &[u8], sample_type: &str, little_endian: bool, count: usize) -> io::Result<Vec<f32>> {
+    let size = sample_size(sample_type)?;
+    if bytes.len() < count * size {
+        return Err(invalid_data(format!("expected {} bytes of samples, got {}", count * size, bytes.len())));
+    }
+    let samples = bytes[bytes.len() - count * size..].chunks_exact(size);
+    let value = |chunk: &[u8]| match size {
+        1 => chunk[0] as f32 / 255.0,
+        2 => {
+            let raw = [chunk[0], chunk[1]];
+            (if little_endian { u16::from_le_bytes(raw) } else { u16::from_be_bytes(raw) }) as f32 / 65535.0
+        }
+        _ => {
+            let raw = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            if little_endian { f32::from_le_bytes(raw) } else { f32::from_be_bytes(raw) }
+        }
+    };
+    Ok(samples.map(value).collect())
+}
+
+// Lets a grid drive anything that takes a texture, e.g. NonUniformMedium. The
+// point is in the space of the grid's bounds, which is world space for media
+// tracked by the integrator.
+impl Texture for VoxelGrid {
+    fn value(&self, _uv: Vector2<f32>, p: Vector3<f32>) -> Vector3<f32> {
+        let v = self.lookup(p);
+        vec(v, v, v)
+    }
+}
+
+// Heterogeneous medium defined by voxel grids, with sigma_a and sigma_s
+// scaled by the density grid like TexturedMedium scales them by its texture.
+// The majorant along a ray is the largest brick maximum it crosses, so rays
+// through empty space cost nothing and shadow rays estimate the residual to
+// each brick's mean with ratio tracking.
+//
+// The temperature (in Kelvin) and emission grids are optional and share the
+// bounds of the density grid. Temperature gives blackbody emission which the
+// emission grid scales, or the emission grid alone gives white light. Like
+// for the other media the radiance is emitted where the medium absorbs.
+pub struct GridMedium {
+    pub density: VoxelGrid,
+    pub temperature: Option<VoxelGrid>,
+    pub emission: Option<VoxelGrid>,
+    // Coefficients per unit length for a voxel value of 1
+    pub sigma_a: Vector3<f32>,
+    pub sigma_s: Vector3<f32>,
+    pub emission_scale: f32,
+    pub phase_function: Arc<dyn Material>,
+}
+
+impl GridMedium {
+    pub fn new(density: VoxelGrid, sigma_a: Vector3<f32>, sigma_s: Vector3<f32>, phase_function: Arc<dyn Material>) -> Self {
+        GridMedium {
+            density,
+            temperature: None,
+            emission: None,
+            sigma_a,
+            sigma_s,
+            emission_scale: 1.0,
+            phase_function,
+        }
+    }
+
+    // Volume filling the bounds of the density grid. The grid stays in world
+    // space unless the volume is placed with NonUniformMedium::transform.
+    pub fn volume(self) -> NonUniformMedium {
+        let bounds = self.density.bounds;
+        let boundary = Transform::new(
+            AABox::new(bounds.max - bounds.min, Arc::clone(&self.phase_function)),
+            (bounds.min + bounds.max) / 2.0,
+            vec_zero(),
+        );
+        NonUniformMedium::from_medium(boundary, self)
+    }
+
+    fn density(&self, p: Vector3<f32>) -> f32 {
+        self.density.lookup(p).max(0.0)
+    }
+
+    fn emitted(&self, ray: &Ray, p: Vector3<f32>) -> Option<Vector3<f32>> {
+        if self.temperature.is_none() && self.emission.is_none() {
+            return None;
+        }
+        let intensity = self.emission.as_ref().map_or(1.0, |e| e.lookup(p)) * self.emission_scale;
+        let temperature = self.temperature.as_ref().map(|t| t.lookup(p));
+        Some(emission(ray, Some(vec_one() * intensity), temperature))
+    }
+}
+
+impl Medium for GridMedium {
+    fn properties(&self, ray: &Ray, p: Vector3<f32>) -> MediumProperties {
+        let (sigma_a, sigma_s) = coefficients(ray, self.sigma_a, self.sigma_s);
+        let density = self.density(p);
+        MediumProperties {
+            sigma_a: sigma_a * density,
+            sigma_s: sigma_s * density,
+            emission: self.emitted(ray, p),
+        }
+    }
+
+    fn majorant(&self, ray: &Ray) -> f32 {
+        let mut max_density: f32 = 0.0;
+        self.density.walk_bricks(ray, 0.0, f32::INFINITY, |brick, _, _| {
+            max_density = max_density.max(self.density.brick_range(brick).0);
+            true
+        });
+        max_density * coefficients(ray, self.sigma_a + self.sigma_s, vec_zero()).0.max()
+    }
+
+    // Residual ratio tracking brick by brick, each with its own bounds
+    fn transmittance(&self, ray: &Ray, t_max: f32) -> Vector3<f32> {
+        let sigma_t = coefficients(ray, self.sigma_a + self.sigma_s, vec_zero()).0;
+        let mut transmittance = vec_one();
+        self.density.walk_bricks(ray, 0.0, t_max, |brick, t0, t1| {
+            let (max_density, mean_density) = self.density.brick_range(brick);
+            if max_density > 0.0 {
+                let mut segment = Ray::new(ray.at(t0), ray.direction()).with_kind(ray.kind);
+                segment.wavelengths = ray.wavelengths;
+                let estimate = residual_ratio_tracking(&segment, t1 - t0, sigma_t, mean_density, max_density, |p| self.density(p));
+                transmittance = transmittance.component_mul(&estimate);
+            }
+            transmittance.max() > 0.0
+        });
+        transmittance
+    }
+
+    fn phase_function(&self) -> Arc<dyn Material> {
+        Arc::clone(&self.phase_function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Isotropic;
+    use crate::spectrum::{self, SampledWavelengths};
+    use crate::texture::ConstantTex;
+
+    fn unit_grid(value: f32) -> VoxelGrid {
+        let bounds = AABB { min: vec(0.0, 0.0, 0.0), max: vec(1.0, 1.0, 1.0) };
+        VoxelGrid::from_dense([2, 2, 2], &[value; 8], bounds)
+    }
+
+    fn medium(sigma_a: f32, sigma_s: f32) -> GridMedium {
+        let phase_function = Arc::new(Isotropic { albedo: ConstantTex::new_arc(vec_one()) });
+        GridMedium::new(unit_grid(1.0), vec_one() * sigma_a, vec_one() * sigma_s, phase_function)
+    }
+
+    // Emission is already at the ray's wavelengths in spectral mode and goes
+    // to the film as is. Averaged over many wavelengths it has to match the
+    // RGB render of the same blackbody.
+    #[test]
+    fn blackbody_grid_matches_in_rgb_and_spectral_mode() {
+        let mut medium = medium(1.0, 0.0);
+        medium.temperature = Some(unit_grid(1800.0));
+        let p = vec(0.5, 0.5, 0.5);
+        let ray = Ray::new(vec(0.5, 0.5, -1.0), vec(0.0, 0.0, 1.0));
+        let rgb = medium.properties(&ray, p).emission.unwrap();
+
+        const SAMPLES: usize = 20000;
+        let mut spectral = Vector3::zeros();
+        for i in 0..SAMPLES {
+            let mut ray = ray.clone();
+            let wavelengths = SampledWavelengths::sample((i as f32 + 0.5) / SAMPLES as f32);
+            ray.wavelengths = Some(wavelengths);
+            spectral += spectrum::to_rgb(medium.properties(&ray, p).emission.unwrap(), &wavelengths);
+        }
+        let spectral = spectral / SAMPLES as f32;
+        for c in 0..3 {
+            assert!((spectral[c] - rgb[c]).abs() < 0.05 * rgb.max(), "{:?} vs {:?}", spectral, rgb);
+        }
+    }
+
+    // A constant grid is Beer-Lambert, counting only the part of the ray
+    // inside the bounds
+    #[test]
+    fn constant_grid_transmittance() {
+        let medium = medium(0.5, 1.5);
+        let ray = Ray::new(vec(0.5, 0.5, -1.0), vec(0.0, 0.0, 1.0));
+        assert_eq!(medium.majorant(&ray), 2.0);
+
+        const SAMPLES: usize = 10000;
+        let mut transmittance = vec_zero();
+        for _ in 0..SAMPLES {
+            transmittance += medium.transmittance(&ray, 1.5);
+        }
+        let expected = (-2.0f32 * 0.5).exp();
+        assert!((transmittance.x / SAMPLES as f32 - expected).abs() < 0.02, "{:?}", transmittance / SAMPLES as f32);
+    }
+}